@@ -0,0 +1,255 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use tide::{http::mime, Body, Middleware, Next, Request, StatusCode};
+use uuid::Uuid;
+
+use crate::json::ApiError;
+use crate::prelude::*;
+use crate::registry::State;
+
+/// Form field carrying the token in html forms.
+pub const FIELD: &str = "csrf_token";
+
+/// Header carrying the token for scripts posting with the session cookie.
+pub const HEADER: &str = "X-CSRF-Token";
+
+const SESSION_KEY: &str = "tide.csrf";
+
+/// Endpoints called by other servers rather than browsers, authenticated by
+/// their own credentials.
+const EXEMPT_PATHS: &[&str] = &["/oauth/token"];
+
+/// Synchronizer token of the current session, set on every request that is
+/// not authenticated by a bearer token. A new token is only kept in the
+/// session once it is handed out through [`issue`].
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
+
+/// Keeps the request's token in the session, for responses that hand it to
+/// the client. Until then a visitor's session holds nothing worth storing.
+pub fn issue(request: &mut Request<State>) -> tide::Result<Option<String>> {
+    let token = match request.ext::<CsrfToken>() {
+        Some(token) => token.0.clone(),
+        None => return Ok(None),
+    };
+    if request.session().get::<String>(SESSION_KEY).as_deref() != Some(token.as_str()) {
+        request.session_mut().insert(SESSION_KEY, &token)?;
+    }
+    Ok(Some(token))
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+/// Rejects unsafe requests whose token does not match the one stored in the
+/// session. Requests carrying a bearer token don't use the session cookie and
+/// are let through, as are JSON API calls made without a session cookie,
+/// such as exchanging a password for a token, which browsers can't send
+/// cross-site without a preflight.
+pub struct CsrfMiddleware {}
+
+impl CsrfMiddleware {
+    fn is_exempt(request: &Request<State>) -> bool {
+        let is_bearer = request
+            .header("Authorization")
+            .is_some_and(|h| h.as_str().starts_with("Bearer "));
+        let is_json = request
+            .content_type()
+            .is_some_and(|ct| ct.essence() == mime::JSON.essence());
+        let has_session = request
+            .cookie(&request.state().config.session_cookie_name)
+            .is_some();
+        let path = request.url().path();
+        is_bearer
+            || EXEMPT_PATHS.contains(&path)
+            || (is_json && !has_session && path.starts_with("/api/"))
+    }
+
+    async fn submitted(request: &mut Request<State>) -> tide::Result<Option<String>> {
+        if let Some(token) = request.header(HEADER) {
+            return Ok(Some(token.as_str().to_string()));
+        }
+
+        let is_form = request
+            .content_type()
+            .is_some_and(|ct| ct.essence() == mime::FORM.essence());
+        if !is_form {
+            return Ok(None);
+        }
+        let bytes = request.body_bytes().await?;
+        let form = Body::from_bytes(bytes.clone())
+            .into_form::<CsrfForm>()
+            .await;
+        request.set_body(bytes);
+        Ok(form.ok().and_then(|f| f.csrf_token))
+    }
+}
+
+#[async_trait]
+impl Middleware<State> for CsrfMiddleware {
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if CsrfMiddleware::is_exempt(&request) {
+            return Ok(next.run(request).await);
+        }
+
+        let stored = request.session().get::<String>(SESSION_KEY);
+
+        if !request.method().is_safe() {
+            let submitted = CsrfMiddleware::submitted(&mut request).await?;
+            let matches = submitted
+                .zip(stored.as_ref())
+                .is_some_and(|(s, token)| constant_time_eq(s.as_bytes(), token.as_bytes()));
+            if !matches {
+                tide::log::warn!(
+                    "rejected {} {}: bad csrf token",
+                    request.method(),
+                    request.url()
+                );
+                return rejected(&request);
+            }
+        }
+
+        let token = stored
+            .unwrap_or_else(|| format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()));
+        request.set_ext(CsrfToken(token));
+        Ok(next.run(request).await)
+    }
+}
+
+fn rejected(request: &Request<State>) -> tide::Result {
+    if request.is_api() {
+        return ApiError::new(
+            StatusCode::Forbidden,
+            "csrf",
+            "missing or invalid csrf token",
+        )
+        .into();
+    }
+    Ok(tide::Response::builder(StatusCode::Forbidden)
+        .body("missing or invalid csrf token")
+        .build())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Url};
+    use tide::{Request, StatusCode};
+
+    use super::{constant_time_eq, issue, CsrfMiddleware, HEADER};
+    use crate::registry::State;
+    use crate::sessions::{SessionBackend, SessionMiddleware};
+
+    fn app() -> tide::Server<State> {
+        let state = State::for_tests();
+        let mut app = tide::with_state(state.clone());
+        app.with(SessionMiddleware::new(
+            SessionBackend::Memory(async_session::MemoryStore::new()),
+            &state.config,
+        ));
+        app.with(CsrfMiddleware {});
+        app.at("/form")
+            .get(|mut req: Request<State>| async move { Ok(issue(&mut req)?.unwrap_or_default()) });
+        app.at("*").post(|_| async { Ok("") });
+        app
+    }
+
+    async fn post(
+        app: &tide::Server<State>,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<(&str, &str)>,
+    ) -> StatusCode {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Post, url);
+        for (name, value) in headers {
+            req.insert_header(*name, *value);
+        }
+        if let Some((content_type, body)) = body {
+            req.set_body(body);
+            req.insert_header("Content-Type", content_type);
+        }
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.status()
+    }
+
+    /// Session cookie and token handed out by a page with a form.
+    async fn token(app: &tide::Server<State>) -> (String, String) {
+        let url = Url::parse("http://localhost/form").unwrap();
+        let mut res: tide::http::Response = app
+            .respond(tide::http::Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        let cookie = res.header("Set-Cookie").unwrap().as_str();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        (cookie, res.body_string().await.unwrap())
+    }
+
+    const FORM: &str = "application/x-www-form-urlencoded";
+
+    #[async_std::test]
+    async fn accepts_the_session_token_only() {
+        let app = app();
+        let (cookie, token) = token(&app).await;
+        let field = format!("csrf_token={}", token);
+        let cookie = [("Cookie", cookie.as_str())];
+        assert_eq!(
+            post(&app, "/x", &cookie, Some((FORM, &field))).await,
+            StatusCode::Ok
+        );
+        let header = [("Cookie", cookie[0].1), (HEADER, token.as_str())];
+        assert_eq!(post(&app, "/x", &header, None).await, StatusCode::Ok);
+
+        let forged = format!("csrf_token={}", "0".repeat(token.len()));
+        assert_eq!(
+            post(&app, "/x", &cookie, Some((FORM, &forged))).await,
+            StatusCode::Forbidden
+        );
+        assert_eq!(post(&app, "/x", &cookie, None).await, StatusCode::Forbidden);
+        // without the session the token is worth nothing
+        assert_eq!(
+            post(&app, "/x", &[], Some((FORM, &field))).await,
+            StatusCode::Forbidden
+        );
+    }
+
+    #[async_std::test]
+    async fn exempts_requests_browsers_cant_forge() {
+        let app = app();
+        let bearer = [("Authorization", "Bearer pat_x")];
+        assert_eq!(post(&app, "/x", &bearer, None).await, StatusCode::Ok);
+        assert_eq!(post(&app, "/oauth/token", &[], None).await, StatusCode::Ok);
+        let json = Some(("application/json", "{}"));
+        assert_eq!(post(&app, "/api/x", &[], json).await, StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn checks_api_calls_that_could_come_from_a_browser() {
+        let app = app();
+        let form = Some((FORM, "a=b"));
+        assert_eq!(post(&app, "/api/x", &[], form).await, StatusCode::Forbidden);
+        let (cookie, _) = token(&app).await;
+        let json = Some(("application/json", "{}"));
+        let cookie = [("Cookie", cookie.as_str())];
+        assert_eq!(
+            post(&app, "/api/x", &cookie, json).await,
+            StatusCode::Forbidden
+        );
+        // json outside the api still needs the token
+        assert_eq!(post(&app, "/x", &[], json).await, StatusCode::Forbidden);
+    }
+
+    #[test]
+    fn compares_tokens_in_full() {
+        assert!(constant_time_eq(b"abcd", b"abcd"));
+        assert!(!constant_time_eq(b"abcd", b"abce"));
+        assert!(!constant_time_eq(b"abcd", b"abc"));
+        assert!(!constant_time_eq(b"", b"a"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
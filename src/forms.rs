@@ -0,0 +1,57 @@
+//! Carries a rejected form submission across the redirect back to the page
+//! with the form, so it can show what went wrong next to each field and
+//! fill in what the user already entered.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use validator::{ValidationError, ValidationErrors};
+
+pub const SESSION_KEY: &str = "tide.form";
+
+/// Fields that are never sent back to the page or kept in the session.
+const SECRET_FIELDS: [&str; 5] = [
+    "password",
+    "confirm_password",
+    "code",
+    "token",
+    "csrf_token",
+];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FormState {
+    /// Validation errors by field name.
+    pub errors: HashMap<String, Vec<ValidationError>>,
+    /// Submitted values by field name, without secrets.
+    pub values: Map<String, Value>,
+}
+
+impl FormState {
+    pub fn new<F: Serialize>(form: &F) -> Self {
+        let mut values = match serde_json::to_value(form) {
+            Ok(Value::Object(values)) => values,
+            _ => Map::new(),
+        };
+        values.retain(|field, _| !SECRET_FIELDS.contains(&field.as_str()));
+        FormState {
+            errors: HashMap::new(),
+            values,
+        }
+    }
+
+    pub fn with_errors(mut self, errors: &ValidationErrors) -> Self {
+        for (field, errors) in errors.field_errors() {
+            let errors = errors.iter().cloned().map(|mut error| {
+                // validator copies the rejected value into the error
+                error.params.remove("value");
+                error
+            });
+            self.errors
+                .entry(field.to_string())
+                .or_default()
+                .extend(errors);
+        }
+        self
+    }
+}
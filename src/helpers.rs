@@ -0,0 +1,272 @@
+use handlebars::{
+    html_escape, Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError,
+};
+
+/// Renders a post body as a constrained subset of markdown. Every piece of
+/// user supplied text is escaped before it is written, the only markup that
+/// can appear in the output is the markup generated here.
+pub fn format_body(body: &str) -> String {
+    let chars: Vec<char> = body.chars().collect();
+    let mut out = String::with_capacity(body.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\r' => i += 1,
+            '\n' => {
+                out.push_str("<br>");
+                i += 1;
+            }
+            '`' => match find_closing(&chars, i + 1, '`') {
+                Some(end) => {
+                    out.push_str("<code>");
+                    out.push_str(&escape(&chars[i + 1..end]));
+                    out.push_str("</code>");
+                    i = end + 1;
+                }
+                None => {
+                    out.push_str(&escape(&chars[i..i + 1]));
+                    i += 1;
+                }
+            },
+            '*' => match find_closing(&chars, i + 1, '*') {
+                Some(end) if end > i + 1 => {
+                    out.push_str("<em>");
+                    out.push_str(&escape(&chars[i + 1..end]));
+                    out.push_str("</em>");
+                    i = end + 1;
+                }
+                _ => {
+                    out.push_str(&escape(&chars[i..i + 1]));
+                    i += 1;
+                }
+            },
+            '@' | '#' if is_boundary(&chars, i) => {
+                let end = scan_while(&chars, i + 1, |c| c.is_alphanumeric() || c == '_');
+                if end > i + 1 {
+                    let name = escape(&chars[i + 1..end]);
+                    let href = if c == '@' {
+                        format!("/users/{}", name)
+                    } else {
+                        format!("/tags/{}", name.to_lowercase())
+                    };
+                    out.push_str(&format!("<a href=\"{}\">{}{}</a>", href, c, name));
+                    i = end;
+                } else {
+                    out.push_str(&escape(&chars[i..i + 1]));
+                    i += 1;
+                }
+            }
+            'h' if is_boundary(&chars, i) && starts_with_scheme(&chars, i) => {
+                let mut end = scan_while(&chars, i, |c| !c.is_whitespace());
+                while end > i && matches!(chars[end - 1], '.' | ',' | '!' | '?' | ')' | ':' | ';') {
+                    end -= 1;
+                }
+                let url = escape(&chars[i..end]);
+                out.push_str(&format!(
+                    "<a href=\"{}\" rel=\"nofollow noopener noreferrer\">{}</a>",
+                    url, url
+                ));
+                i = end;
+            }
+            _ => {
+                let end = scan_while(&chars, i + 1, |c| {
+                    !matches!(c, '\r' | '\n' | '`' | '*' | '@' | '#' | 'h')
+                });
+                out.push_str(&escape(&chars[i..end]));
+                i = end;
+            }
+        }
+    }
+
+    out
+}
+
+/// Handlebars helper exposing [`format_body`] as `{{format post.body}}`.
+pub fn format_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let body = h
+        .param(0)
+        .ok_or_else(|| RenderError::new("format: missing body parameter"))?;
+    match body.value().as_str() {
+        Some(body) => out.write(&format_body(body))?,
+        None if body.value().is_null() => {}
+        None => out.write(&html_escape(&body.value().to_string()))?,
+    }
+    Ok(())
+}
+
+/// Handlebars helper writing the hidden csrf field, `{{csrf_field}}`. The
+/// token is taken from the root context so it works inside blocks too.
+pub fn csrf_field_helper(
+    _: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    if let Some(token) = ctx.data().get(crate::csrf::FIELD).and_then(|t| t.as_str()) {
+        out.write(&format!(
+            "<input type=\"hidden\" name=\"{}\" value=\"{}\" />",
+            crate::csrf::FIELD,
+            html_escape(token)
+        ))?;
+    }
+    Ok(())
+}
+
+fn escape(chars: &[char]) -> String {
+    html_escape(&chars.iter().collect::<String>())
+}
+
+fn find_closing(chars: &[char], start: usize, delim: char) -> Option<usize> {
+    chars[start..]
+        .iter()
+        .take_while(|c| **c != '\n')
+        .position(|c| *c == delim)
+        .map(|p| start + p)
+}
+
+fn scan_while<F: Fn(char) -> bool>(chars: &[char], start: usize, f: F) -> usize {
+    let mut end = start;
+    while end < chars.len() && f(chars[end]) {
+        end += 1;
+    }
+    end
+}
+
+fn is_boundary(chars: &[char], i: usize) -> bool {
+    i == 0 || !(chars[i - 1].is_alphanumeric() || chars[i - 1] == '_')
+}
+
+fn starts_with_scheme(chars: &[char], i: usize) -> bool {
+    let rest: String = chars[i..chars.len().min(i + 8)].iter().collect();
+    rest.starts_with("https://") || rest.starts_with("http://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_body;
+    use proptest::prelude::*;
+
+    const LINK_REL: &str = "\" rel=\"nofollow noopener noreferrer\">";
+
+    /// Walks the output and checks the only markup in it is the one
+    /// `format_body` generates, returning the link targets.
+    fn check_markup(html: &str) -> Result<Vec<String>, String> {
+        let mut hrefs = Vec::new();
+        let mut rest = html;
+        while let Some(start) = rest.find(['<', '>', '"']) {
+            let tail = &rest[start..];
+            let fixed = ["<br>", "<code>", "</code>", "<em>", "</em>", "</a>"];
+            if let Some(tag) = fixed.iter().find(|tag| tail.starts_with(**tag)) {
+                rest = &tail[tag.len()..];
+                continue;
+            }
+            let href = tail
+                .strip_prefix("<a href=\"")
+                .ok_or_else(|| format!("unexpected markup at {:?}", tail))?;
+            let end = href
+                .find('"')
+                .ok_or_else(|| format!("unterminated href in {:?}", tail))?;
+            let (value, after) = href.split_at(end);
+            if value.contains(['<', '>', '\'']) {
+                return Err(format!("unescaped href {:?}", value));
+            }
+            rest = if let Some(after) = after.strip_prefix(LINK_REL) {
+                after
+            } else if let Some(after) = after.strip_prefix("\">") {
+                after
+            } else {
+                return Err(format!("unexpected attributes in {:?}", tail));
+            };
+            hrefs.push(value.to_string());
+        }
+        Ok(hrefs)
+    }
+
+    fn markdown_like() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            any::<String>(),
+            "[a-z<>\"'&= ]{0,8}",
+            Just(String::from("`")),
+            Just(String::from("*")),
+            Just(String::from("@")),
+            Just(String::from("#")),
+            Just(String::from("\n")),
+            Just(String::from("http://")),
+            Just(String::from("https://")),
+            Just(String::from("javascript:")),
+            Just(String::from("\"><script>")),
+        ];
+        prop::collection::vec(piece, 0..16).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn no_markup_is_injected(body in markdown_like()) {
+            let html = format_body(&body);
+            let hrefs = check_markup(&html).map_err(TestCaseError::fail)?;
+            for href in hrefs {
+                prop_assert!(
+                    href.starts_with("/users/")
+                        || href.starts_with("/tags/")
+                        || href.starts_with("http://")
+                        || href.starts_with("https://"),
+                    "unexpected link target {:?}",
+                    href
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            format_body("<script>alert(\"x\")</script>"),
+            "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;"
+        );
+    }
+
+    #[test]
+    fn formats_emphasis_and_code() {
+        assert_eq!(format_body("*hi* there"), "<em>hi</em> there");
+        assert_eq!(format_body("a `*b*` c"), "a <code>*b*</code> c");
+        assert_eq!(format_body("2 * 3"), "2 * 3");
+        assert_eq!(format_body("**"), "**");
+        assert_eq!(format_body("one\ntwo"), "one<br>two");
+    }
+
+    #[test]
+    fn links_mentions_and_hashtags() {
+        assert_eq!(
+            format_body("hey @bob_1!"),
+            "hey <a href=\"/users/bob_1\">@bob_1</a>!"
+        );
+        assert_eq!(
+            format_body("#Rust rocks"),
+            "<a href=\"/tags/rust\">#Rust</a> rocks"
+        );
+        assert_eq!(format_body("mail@example"), "mail@example");
+        assert_eq!(format_body("@ alone"), "@ alone");
+    }
+
+    #[test]
+    fn trims_trailing_punctuation_from_urls() {
+        assert_eq!(
+            format_body("see https://example.com/a?b=1."),
+            "see <a href=\"https://example.com/a?b&#x3D;1\" rel=\"nofollow noopener noreferrer\">https://example.com/a?b&#x3D;1</a>."
+        );
+        assert_eq!(
+            format_body("(http://example.com)"),
+            "(<a href=\"http://example.com\" rel=\"nofollow noopener noreferrer\">http://example.com</a>)"
+        );
+        assert_eq!(format_body("javascript:alert(1)"), "javascript:alert(1)");
+    }
+}
@@ -0,0 +1,49 @@
+//! Background worker. Runs queued jobs one at a time, purges accounts whose
+//! deletion grace period has ended and drops expired in-memory sessions.
+
+use std::time::Duration;
+
+use crate::registry::State;
+use crate::repos::job::{Job, JobKind, JobRepository};
+
+pub mod deletion;
+pub mod export;
+pub mod import;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Starts the worker on its own task for the lifetime of the process.
+pub fn spawn(state: State) {
+    async_std::task::spawn(async move {
+        loop {
+            if let Err(e) = tick(&state).await {
+                tide::log::error!("background worker failed: {}", e);
+            }
+            async_std::task::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+async fn tick(state: &State) -> tide::Result<()> {
+    state.session_store.cleanup().await?;
+    state.jobs().requeue_stale().await?;
+    deletion::purge_due(state).await?;
+    while let Some(job) = state.jobs().claim_next().await? {
+        let error = match run(state, &job).await {
+            Ok(_) => None,
+            Err(e) => {
+                tide::log::error!("job {} failed: {}", job._id, e);
+                Some(e.to_string())
+            }
+        };
+        state.jobs().finish(&job._id, error).await?;
+    }
+    Ok(())
+}
+
+async fn run(state: &State, job: &Job) -> tide::Result<()> {
+    match job.kind {
+        JobKind::Export => export::run(state, job).await,
+        JobKind::Import => import::run(state, job).await,
+    }
+}
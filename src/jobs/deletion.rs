@@ -0,0 +1,121 @@
+use mongodb::bson::doc;
+
+use super::{export, import};
+use crate::registry::State;
+use crate::repos::follow::FollowRepository;
+use crate::repos::job::JobRepository;
+use crate::repos::notification::NotificationRepository;
+use crate::repos::oauth::{GrantRepository, OAuthAppRepository};
+use crate::repos::token::AccessTokenRepository;
+use crate::repos::user::{User, UserRepository};
+use crate::timestamp;
+
+/// Purges every account whose deletion grace period has ended.
+pub async fn purge_due(state: &State) -> tide::Result<()> {
+    for user in state.users().due_for_deletion(timestamp()).await? {
+        let uid = user._id.clone();
+        purge(state, user).await?;
+        tide::log::info!("purged deleted account {}", uid);
+    }
+    Ok(())
+}
+
+/// Removes everything the user owns and turns the account into a
+/// tombstone. Audit events and reports stay, but point at the tombstone and
+/// lose the user's IP addresses and user agents.
+pub async fn purge(state: &State, mut user: User) -> tide::Result<()> {
+    let uid = user._id.clone();
+    let owned = doc! { "uid": &uid };
+
+    state.destroy_sessions(&uid, None).await?;
+    state
+        .refresh_tokens()
+        .delete_many(owned.clone(), None)
+        .await?;
+    state
+        .access_tokens()
+        .delete_many(owned.clone(), None)
+        .await?;
+    state.identities().delete_many(owned.clone(), None).await?;
+    state.posts().delete_many(owned.clone(), None).await?;
+    state.follows().remove_all(&uid).await?;
+    state.notifications().remove_all(&uid).await?;
+    state.grants().delete_many(owned.clone(), None).await?;
+    state
+        .authorization_codes()
+        .delete_many(owned.clone(), None)
+        .await?;
+    for app in state.oauth_apps().list(&uid).await? {
+        state.grants().remove_client(&app._id).await?;
+        state.access_tokens().revoke_app(&app._id).await?;
+        state.oauth_apps().remove(&uid, &app._id).await?;
+    }
+    for job in state.jobs().list(&uid).await? {
+        remove_archives(state, &job._id).await;
+    }
+    state.jobs().remove_all(&uid).await?;
+    state
+        .lockouts()
+        .delete_many(
+            doc! { "kind": "username", "value": user.username.to_lowercase() },
+            None,
+        )
+        .await?;
+    state
+        .audit_events()
+        .update_many(
+            owned,
+            doc! { "$set": { "ip": null, "user_agent": null } },
+            None,
+        )
+        .await?;
+
+    user.tombstone();
+    state.users().update(user).await?;
+    Ok(())
+}
+
+/// Deletes whatever archives the job `id` left on disk, an export it wrote
+/// or an upload it was importing.
+async fn remove_archives(state: &State, id: &str) {
+    let _ = async_std::fs::remove_file(export::path(state, id)).await;
+    let _ = async_std::fs::remove_file(import::path(state, id)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{purge_due, remove_archives};
+    use crate::config::Config;
+    use crate::jobs::{export, import};
+    use crate::registry::State;
+
+    #[async_std::test]
+    async fn removes_the_archives_of_every_kind_of_job() {
+        let dir = std::env::temp_dir().join(format!("purge-{}", uuid::Uuid::new_v4()));
+        let mut state = State::for_tests();
+        state.config = Arc::new(Config {
+            export_dir: dir.join("exports"),
+            import_dir: dir.join("imports"),
+            ..(*state.config).clone()
+        });
+        std::fs::create_dir_all(&state.config.export_dir).unwrap();
+        std::fs::create_dir_all(&state.config.import_dir).unwrap();
+        std::fs::write(export::path(&state, "a"), b"zip").unwrap();
+        std::fs::write(import::path(&state, "b"), b"zip").unwrap();
+
+        remove_archives(&state, "a").await;
+        remove_archives(&state, "b").await;
+        // jobs that never wrote an archive are fine too
+        remove_archives(&state, "c").await;
+        assert!(!export::path(&state, "a").exists());
+        assert!(!import::path(&state, "b").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn stops_when_the_database_is_unavailable() {
+        assert!(purge_due(&State::for_tests()).await.is_err());
+    }
+}
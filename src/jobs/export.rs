@@ -0,0 +1,241 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::mailer::Email;
+use crate::registry::State;
+use crate::repos::audit::{AuditFilter, AuditRepository};
+use crate::repos::follow::FollowRepository;
+use crate::repos::identity::IdentityRepository;
+use crate::repos::job::{Job, JobRepository};
+use crate::repos::notification::NotificationRepository;
+use crate::repos::oauth::{GrantRepository, OAuthAppRepository};
+use crate::repos::post::PostRepository;
+use crate::repos::report::{Report, ReportRepository};
+use crate::repos::session::SessionRepository;
+use crate::repos::token::AccessTokenRepository;
+use crate::repos::user::{User, UserRepository};
+
+/// How long a finished export can be downloaded.
+pub const EXPORT_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// Where the archive of the export job `id` is written.
+pub fn path(state: &State, id: &str) -> PathBuf {
+    state.config.export_dir.join(format!("{}.zip", id))
+}
+
+/// Collects everything stored about the user into a zip of JSON files and
+/// emails them once it can be downloaded.
+pub async fn run(state: &State, job: &Job) -> tide::Result<()> {
+    let user = state.users().get_by_id(&job.uid).await?;
+    let files = collect(state, &user).await?;
+    state.jobs().touch(&job._id).await?;
+
+    async_std::fs::create_dir_all(&state.config.export_dir).await?;
+    let path = path(state, &job._id);
+    async_std::task::spawn_blocking(move || write_zip(&path, files)).await?;
+
+    let link = format!("{}/account/export/download", state.config.app_url);
+    let body = state.render(
+        "export-ready.txt",
+        &json!({ "username": user.username, "link": link }),
+    )?;
+    state
+        .mailer
+        .send(Email {
+            to: user.username.clone(),
+            subject: String::from("Your data export is ready"),
+            body,
+        })
+        .await
+}
+
+fn file<T: Serialize>(name: &str, data: &T) -> tide::Result<(String, Vec<u8>)> {
+    Ok((name.to_string(), serde_json::to_vec_pretty(data)?))
+}
+
+async fn collect(state: &State, user: &User) -> tide::Result<Vec<(String, Vec<u8>)>> {
+    let uid = &user._id;
+    let profile = json!({
+        "id": user._id,
+        "username": user.username,
+        "role": user.role,
+        "email_verified": user.email_verified,
+        "totp_enabled": user.totp_enabled,
+        "created_at": user.created_at,
+        "last_login_at": user.last_login_at,
+    });
+    // hashes of tokens, app secrets and session ids are left out
+    let tokens: Vec<_> = state
+        .access_tokens()
+        .list(uid)
+        .await?
+        .into_iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "scopes": t.scopes,
+                "created_at": t.created_at,
+                "expires_at": t.expires_at,
+                "last_used_at": t.last_used_at,
+                "revoked": t.revoked,
+            })
+        })
+        .collect();
+    let sessions: Vec<_> = state
+        .sessions()
+        .list(uid)
+        .await?
+        .into_iter()
+        .map(|s| {
+            json!({
+                "user_agent": s.user_agent,
+                "ip": s.ip,
+                "created_at": s.created_at,
+                "last_seen_at": s.last_seen_at,
+                "expires_at": s.expires_at,
+            })
+        })
+        .collect();
+    let apps: Vec<_> = state
+        .oauth_apps()
+        .list(uid)
+        .await?
+        .into_iter()
+        .map(|a| {
+            json!({
+                "client_id": a._id,
+                "name": a.name,
+                "redirect_uris": a.redirect_uris,
+                "created_at": a.created_at,
+            })
+        })
+        .collect();
+    let reports: Vec<_> = state
+        .reports()
+        .filed_by(uid)
+        .await?
+        .into_iter()
+        .filter_map(|r| own_report(uid, r))
+        .collect();
+    let events_filter = AuditFilter {
+        uid: Some(uid.clone()),
+        kind: None,
+    };
+
+    Ok(vec![
+        file("profile.json", &profile)?,
+        file("sessions.json", &sessions)?,
+        file("identities.json", &state.identities().list(uid).await?)?,
+        file("access_tokens.json", &tokens)?,
+        file("oauth_apps.json", &apps)?,
+        file("authorized_apps.json", &state.grants().list(uid).await?)?,
+        file("reports.json", &reports)?,
+        file("posts.json", &state.posts().list(uid).await?)?,
+        file("following.json", &state.follows().following(uid).await?)?,
+        file(
+            "notifications.json",
+            &state.notifications().list(uid, 0).await?,
+        )?,
+        // a limit of 0 returns every event
+        file(
+            "audit_events.json",
+            &state.audit_events().list(&events_filter, 0).await?,
+        )?,
+    ])
+}
+
+/// Only the user's own part of a report they filed, not what others said or
+/// which moderator handled it.
+fn own_report(uid: &str, report: Report) -> Option<serde_json::Value> {
+    let reporter = report.reporters.into_iter().find(|p| p.uid == uid)?;
+    Some(json!({
+        "target": report.target,
+        "reason": reporter.reason,
+        "comment": reporter.comment,
+        "created_at": reporter.created_at,
+        "status": report.status,
+        "outcome": report.outcome,
+    }))
+}
+
+/// Writes the archive next to `path` first, so a partly written file is
+/// never offered for download.
+fn write_zip(path: &Path, files: Vec<(String, Vec<u8>)>) -> std::io::Result<()> {
+    let partial = path.with_extension("zip.partial");
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&partial)?);
+    let options = zip::write::FileOptions::default();
+    for (name, contents) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&contents)?;
+    }
+    zip.finish()?;
+    std::fs::rename(partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{own_report, write_zip};
+    use crate::repos::report::{Reason, Report, ReportStatus, ReportTarget, Reporter, TargetKind};
+
+    fn reporter(uid: &str, comment: &str) -> Reporter {
+        Reporter {
+            uid: uid.to_string(),
+            reason: Reason::Spam,
+            comment: Some(comment.to_string()),
+            created_at: 1,
+        }
+    }
+
+    #[test]
+    fn exports_only_the_users_part_of_a_report() {
+        let report = Report {
+            _id: String::from("r"),
+            target: ReportTarget {
+                kind: TargetKind::Account,
+                id: String::from("t"),
+            },
+            reporters: vec![reporter("a", "mine"), reporter("b", "theirs")],
+            status: ReportStatus::Resolved,
+            moderator: Some(String::from("m")),
+            outcome: None,
+            note: Some(String::from("moderator note")),
+            created_at: 1,
+            resolved_at: Some(2),
+        };
+        let exported = own_report("a", report.clone()).unwrap().to_string();
+        assert!(exported.contains("mine"));
+        for hidden in ["theirs", "moderator note", "\"m\""] {
+            assert!(!exported.contains(hidden), "{} leaked", hidden);
+        }
+        assert!(own_report("c", report).is_none());
+    }
+
+    #[test]
+    fn writes_every_file_and_no_partial_archive() {
+        let dir = std::env::temp_dir().join(format!("export-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("job.zip");
+        let files = vec![
+            (String::from("profile.json"), b"{}".to_vec()),
+            (String::from("posts.json"), b"[]".to_vec()),
+        ];
+        write_zip(&path, files).unwrap();
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let mut posts = String::new();
+        zip.by_name("posts.json")
+            .unwrap()
+            .read_to_string(&mut posts)
+            .unwrap();
+        assert_eq!(zip.len(), 2);
+        assert_eq!(posts, "[]");
+        assert!(!path.with_extension("zip.partial").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::registry::State;
+use crate::repos::job::{Job, JobRepository};
+use crate::repos::post::{Post, PostRepository};
+
+/// Tweets imported between checkpoints.
+const BATCH: usize = 100;
+
+/// Largest archive accepted for upload.
+pub const MAX_ARCHIVE_SIZE: u64 = 1 << 30;
+
+/// Most the tweets parts of an archive may take up once decompressed, so a
+/// crafted archive can't exhaust memory.
+const MAX_TWEETS_SIZE: u64 = 256 << 20;
+
+/// Where the uploaded archive of the import job `id` is kept.
+pub fn path(state: &State, id: &str) -> PathBuf {
+    state.config.import_dir.join(format!("{}.zip", id))
+}
+
+/// A tweet read from the archive, reduced to what a post keeps of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tweet {
+    pub id: String,
+    pub text: String,
+    pub created_at: usize,
+    pub in_reply_to: Option<String>,
+    pub media: Vec<String>,
+}
+
+impl Tweet {
+    fn into_post(self, uid: &str) -> Post {
+        Post {
+            _id: Uuid::new_v4().to_string(),
+            uid: uid.to_string(),
+            body: self.text,
+            created_at: self.created_at,
+            imported_from: Some(self.id),
+            in_reply_to: self.in_reply_to,
+            media: self.media,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Wrapped {
+        tweet: ArchivedTweet,
+    },
+    /// Older archives list the tweets without the wrapper.
+    Bare(ArchivedTweet),
+}
+
+#[derive(Deserialize)]
+struct ArchivedTweet {
+    id_str: String,
+    full_text: String,
+    created_at: String,
+    in_reply_to_status_id_str: Option<String>,
+    extended_entities: Option<Entities>,
+}
+
+#[derive(Deserialize)]
+struct Entities {
+    #[serde(default)]
+    media: Vec<Media>,
+}
+
+#[derive(Deserialize)]
+struct Media {
+    media_url_https: String,
+}
+
+fn invalid(message: impl ToString) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Parses one `data/tweets.js` part. The file is a script assigning a JSON
+/// array, `window.YTD.tweets.part0 = [...]`.
+pub fn parse_tweets(script: &str) -> Result<Vec<Tweet>, Error> {
+    let json = script
+        .split_once('=')
+        .map(|(_, json)| json)
+        .ok_or_else(|| invalid("not a tweets.js file"))?;
+    let entries: Vec<Entry> = serde_json::from_str(json.trim()).map_err(invalid)?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let tweet = match entry {
+                Entry::Wrapped { tweet } | Entry::Bare(tweet) => tweet,
+            };
+            let created_at = OffsetDateTime::parse(&tweet.created_at, "%a %b %d %T %z %Y")
+                .map_err(invalid)?
+                .unix_timestamp();
+            Ok(Tweet {
+                id: tweet.id_str,
+                // posts are escaped when rendered
+                text: tweet
+                    .full_text
+                    .replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&amp;", "&"),
+                created_at: created_at.max(0) as usize,
+                in_reply_to: tweet.in_reply_to_status_id_str,
+                media: tweet
+                    .extended_entities
+                    .map(|e| e.media.into_iter().map(|m| m.media_url_https).collect())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Reads every tweets part of the archive at `path`, oldest first. Fails
+/// once the parts decompress to more than `limit` bytes.
+pub fn read_archive(path: &Path, limit: u64) -> Result<Vec<Tweet>, Error> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| is_tweets_part(name))
+        .map(String::from)
+        .collect();
+    if names.is_empty() {
+        return Err(invalid("the archive has no data/tweets.js"));
+    }
+    names.sort();
+    let mut tweets = Vec::new();
+    let mut left = limit;
+    for name in names {
+        let mut script = String::new();
+        // the sizes in the zip headers can't be trusted
+        let read = archive
+            .by_name(&name)?
+            .take(left + 1)
+            .read_to_string(&mut script)? as u64;
+        if read > left {
+            return Err(invalid("the tweets in the archive are too large"));
+        }
+        left -= read;
+        tweets.extend(parse_tweets(&script)?);
+    }
+    // a fixed order keeps the checkpoint of a resumed import meaningful
+    tweets.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    Ok(tweets)
+}
+
+/// `data/tweets.js` and the `data/tweets-part1.js` it is split into when
+/// large, or `data/tweet.js` in older archives.
+fn is_tweets_part(name: &str) -> bool {
+    name == "data/tweet.js"
+        || name == "data/tweets.js"
+        || (name.starts_with("data/tweets-part") && name.ends_with(".js"))
+}
+
+/// What is left to import after `checkpoint` tweets.
+fn remaining(tweets: &[Tweet], checkpoint: usize) -> &[Tweet] {
+    tweets.get(checkpoint..).unwrap_or_default()
+}
+
+/// Imports the uploaded archive as posts of the user, keeping the original
+/// timestamps. Progress is checkpointed on the job after every batch, posts
+/// of a batch that was cut short are skipped when it is run again. The
+/// archive is removed once the job is done, whether it succeeded or not.
+pub async fn run(state: &State, job: &Job) -> tide::Result<()> {
+    let archive = path(state, &job._id);
+    let result = import(state, job, archive.clone()).await;
+    let _ = async_std::fs::remove_file(archive).await;
+    result
+}
+
+async fn import(state: &State, job: &Job, archive: PathBuf) -> tide::Result<()> {
+    let tweets =
+        async_std::task::spawn_blocking(move || read_archive(&archive, MAX_TWEETS_SIZE)).await?;
+
+    let mut done = job.checkpoint;
+    for batch in remaining(&tweets, done).chunks(BATCH) {
+        for tweet in batch {
+            let post = tweet.clone().into_post(&job.uid);
+            state.posts().import(&post).await?;
+        }
+        done += batch.len();
+        state
+            .jobs()
+            .checkpoint(&job._id, done, tweets.len())
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::{is_tweets_part, parse_tweets, read_archive, remaining, Tweet};
+
+    const PART: &str = r#"window.YTD.tweets.part0 = [
+  {
+    "tweet" : {
+      "id_str" : "1050118621198921728",
+      "full_text" : "fish &amp; chips &lt;3",
+      "created_at" : "Wed Oct 10 20:19:24 +0000 2018",
+      "extended_entities" : {
+        "media" : [ { "media_url_https" : "https://pbs.twimg.com/media/a.jpg" } ]
+      }
+    }
+  },
+  {
+    "tweet" : {
+      "id_str" : "1050118621198921729",
+      "full_text" : "@someone agreed",
+      "created_at" : "Wed Oct 10 20:20:00 +0000 2018",
+      "in_reply_to_status_id_str" : "1050118621198921700"
+    }
+  }
+]"#;
+
+    #[test]
+    fn parses_tweets_and_replies() {
+        let tweets = parse_tweets(PART).unwrap();
+        assert_eq!(
+            tweets[0],
+            Tweet {
+                id: String::from("1050118621198921728"),
+                text: String::from("fish & chips <3"),
+                created_at: 1539202764,
+                in_reply_to: None,
+                media: vec![String::from("https://pbs.twimg.com/media/a.jpg")],
+            }
+        );
+        assert_eq!(tweets[1].created_at, 1539202800);
+        assert_eq!(
+            tweets[1].in_reply_to.as_deref(),
+            Some("1050118621198921700")
+        );
+    }
+
+    #[test]
+    fn parses_archives_without_the_tweet_wrapper() {
+        let script = r#"window.YTD.tweet.part0 = [{
+            "id_str": "1", "full_text": "hi", "created_at": "Thu Jan 03 10:00:00 +0000 2019"
+        }]"#;
+        assert_eq!(parse_tweets(script).unwrap()[0].text, "hi");
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(parse_tweets("[]").is_err());
+        assert!(parse_tweets("window.YTD.tweets.part0 = {}").is_err());
+        assert!(!is_tweets_part("data/like.js"));
+        assert!(is_tweets_part("data/tweets-part1.js"));
+    }
+
+    #[test]
+    fn resumes_after_the_checkpoint() {
+        let tweets = parse_tweets(PART).unwrap();
+        assert_eq!(remaining(&tweets, 0).len(), 2);
+        assert_eq!(remaining(&tweets, 1)[0].id, "1050118621198921729");
+        assert!(remaining(&tweets, 2).is_empty());
+        assert!(remaining(&tweets, 5).is_empty());
+    }
+
+    fn archive(name: &str, parts: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.zip", name, uuid::Uuid::new_v4()));
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for (name, contents) in parts {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_every_part_of_an_archive() {
+        let path = archive(
+            "tweets",
+            &[("data/tweets.js", PART), ("data/like.js", "not a tweet")],
+        );
+        let tweets = read_archive(&path, PART.len() as u64).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(tweets.len(), 2);
+    }
+
+    #[test]
+    fn refuses_archives_that_decompress_past_the_limit() {
+        let path = archive(
+            "bomb",
+            &[("data/tweets.js", PART), ("data/tweets-part1.js", PART)],
+        );
+        let result = read_archive(&path, PART.len() as u64 + 10);
+        std::fs::remove_file(path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use tide::{Body, StatusCode};
+use validator::ValidationErrors;
+
+use crate::forms::FormState;
+
+pub struct JsonResponse<T: Serialize> {
+    code: StatusCode,
+    data: T,
+}
+
+impl<T: Serialize> JsonResponse<T> {
+    pub fn new(data: T) -> Self {
+        JsonResponse {
+            code: StatusCode::Ok,
+            data,
+        }
+    }
+
+    pub fn with_status(mut self, code: StatusCode) -> Self {
+        self.code = code;
+        self
+    }
+}
+
+impl<T: Serialize> From<JsonResponse<T>> for tide::Result {
+    fn from(res: JsonResponse<T>) -> Self {
+        let res = tide::Response::builder(res.code)
+            .body(Body::from_json(&res.data)?)
+            .build();
+        Ok(res)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    code: StatusCode,
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(code: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            error,
+            message: message.into(),
+            fields: None,
+        }
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::Unauthorized, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::Forbidden, "forbidden", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NotFound, "not_found", message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::Conflict, "conflict", message)
+    }
+
+    pub fn unprocessable(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UnprocessableEntity, "unprocessable", message)
+    }
+
+    pub fn too_many_requests() -> Self {
+        ApiError::new(
+            StatusCode::TooManyRequests,
+            "too_many_requests",
+            "too many failed attempts, try again later",
+        )
+    }
+
+    /// 422 listing the errors of each field, without the submitted values
+    /// validator copies into them, which would echo passwords back.
+    pub fn validation(errors: &ValidationErrors) -> Self {
+        ApiError {
+            fields: Some(json!(FormState::default().with_errors(errors).errors)),
+            ..ApiError::new(
+                StatusCode::UnprocessableEntity,
+                "validation",
+                "One or more fields are invalid",
+            )
+        }
+    }
+}
+
+impl From<ApiError> for tide::Result {
+    fn from(err: ApiError) -> Self {
+        JsonResponse::new(&err).with_status(err.code).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use validator::Validate;
+
+    use super::ApiError;
+
+    #[derive(Deserialize, Validate)]
+    struct Form {
+        #[validate(length(min = 10, code = "length"))]
+        password: String,
+    }
+
+    #[test]
+    fn validation_errors_leave_out_the_submitted_values() {
+        let form = Form {
+            password: String::from("hunter2"),
+        };
+        let error = ApiError::validation(&form.validate().unwrap_err());
+        let body = serde_json::to_value(&error).unwrap();
+        assert_eq!(body["error"], "validation");
+        assert_eq!(body["fields"]["password"][0]["code"], "length");
+        assert_eq!(body["fields"]["password"][0]["params"]["min"], 10);
+        assert!(!body.to_string().contains("hunter2"));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tide::{Middleware, Next, Request};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::json::ApiError;
+use crate::registry::State;
+use crate::repos::token::{hash_token, AccessToken, AccessTokenRepository, Scopes};
+use crate::repos::user::UserRepository;
+use crate::{timestamp, Claims};
+
+#[derive(Clone)]
+struct SigningKey {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+/// Set of signing keys indexed by `kid`. Tokens are always signed with the
+/// active key, any key still in the ring can be used to verify, which lets
+/// keys be rotated without invalidating tokens that are already out.
+#[derive(Clone)]
+pub struct Keyring {
+    active: String,
+    keys: Arc<HashMap<String, SigningKey>>,
+}
+
+impl Keyring {
+    /// Loads keys from `jwt_keys`, a comma separated list of
+    /// `kid:HS256:secret` or `kid:EdDSA:private.pem:public.pem` entries.
+    /// `jwt_kid` selects the signing key and defaults to the first entry.
+    /// Without `jwt_keys` the session secret is used as a single HS256 key.
+    pub fn from_config(config: &Config) -> Result<Keyring, String> {
+        let spec = config
+            .jwt_keys
+            .clone()
+            .unwrap_or_else(|| format!("default:HS256:{}", config.session_secret));
+
+        let mut keys = HashMap::new();
+        let mut first = None;
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            // the secret of an HS256 key may itself contain ':'
+            let parts: Vec<&str> = entry.splitn(3, ':').collect();
+            let key = match parts.as_slice() {
+                [_, "HS256", secret] => SigningKey {
+                    algorithm: Algorithm::HS256,
+                    encoding: EncodingKey::from_secret(secret.as_bytes()),
+                    decoding: DecodingKey::from_secret(secret.as_bytes()),
+                },
+                [kid, "EdDSA", files] => {
+                    let (private, public) = files
+                        .split_once(':')
+                        .ok_or_else(|| format!("invalid jwt_keys entry for {}", kid))?;
+                    let read = |path: &str| {
+                        std::fs::read(path).map_err(|e| format!("jwt key {}: {}: {}", kid, path, e))
+                    };
+                    SigningKey {
+                        algorithm: Algorithm::EdDSA,
+                        encoding: EncodingKey::from_ed_pem(&read(private)?)
+                            .map_err(|e| format!("jwt key {}: invalid private key: {}", kid, e))?,
+                        decoding: DecodingKey::from_ed_pem(&read(public)?)
+                            .map_err(|e| format!("jwt key {}: invalid public key: {}", kid, e))?,
+                    }
+                }
+                _ => return Err(format!("invalid jwt_keys entry for {}", parts[0])),
+            };
+            first.get_or_insert_with(|| parts[0].to_string());
+            keys.insert(parts[0].to_string(), key);
+        }
+
+        let active = config
+            .jwt_kid
+            .clone()
+            .or(first)
+            .ok_or("jwt_keys must have at least one key")?;
+        if !keys.contains_key(&active) {
+            return Err(format!("jwt_kid {} does not match a key", active));
+        }
+
+        Ok(Keyring {
+            active,
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let key = &self.keys[&self.active];
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(self.active.clone());
+        jsonwebtoken::encode(&header, claims, &key.encoding)
+    }
+
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> Result<T, jsonwebtoken::errors::Error> {
+        use jsonwebtoken::errors::ErrorKind;
+
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = header
+            .kid
+            .and_then(|kid| self.keys.get(&kid))
+            .ok_or(ErrorKind::InvalidToken)?;
+        if key.algorithm != header.alg {
+            return Err(ErrorKind::InvalidAlgorithm.into());
+        }
+
+        let validation = Validation::new(key.algorithm);
+        jsonwebtoken::decode::<T>(token, &key.decoding, &validation).map(|data| data.claims)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+/// Signed token sent by email. `jti` is recorded once the token is used so
+/// it can't be used again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailToken {
+    pub sub: String,
+    pub purpose: Purpose,
+    pub exp: usize,
+    pub jti: String,
+}
+
+impl EmailToken {
+    pub fn new(uid: &str, purpose: Purpose, ttl: Duration) -> Self {
+        EmailToken {
+            sub: uid.to_string(),
+            purpose,
+            exp: timestamp() + ttl.as_secs() as usize,
+            jti: Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// Authenticates requests carrying an `Authorization: Bearer` header, either
+/// a signed JWT or a personal access token. On success the request gets the
+/// same `User` extension a session login sets, along with the token `Claims`.
+pub struct BearerMiddleware {}
+
+#[async_trait]
+impl Middleware<State> for BearerMiddleware {
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let token = request
+            .header("Authorization")
+            .and_then(|h| h.as_str().strip_prefix("Bearer "))
+            .map(|t| t.trim().to_string());
+
+        if let Some(token) = token.as_deref().filter(|t| AccessToken::is_prefixed(t)) {
+            let tokens = request.state().access_tokens();
+            let access_token = match tokens.get_active(&hash_token(token)).await {
+                Ok(access_token) => access_token,
+                Err(_) => return invalid_token(),
+            };
+            match request.state().users().get_by_id(&access_token.uid).await {
+                Ok(user) => {
+                    let lifetime = request.state().config.session_lifetime();
+                    let ttl = access_token.expires_at.map_or(lifetime, |exp| {
+                        Duration::from_secs(exp.saturating_sub(timestamp()) as u64)
+                    });
+                    let mut claims = Claims::new(&user, ttl);
+                    claims.totp = user.totp_enabled.then_some(claims.exp);
+                    tokens.touch(&access_token._id).await?;
+                    request.set_ext(user);
+                    request.set_ext(claims);
+                    request.set_ext(Scopes(access_token.scopes));
+                }
+                Err(_) => return invalid_token(),
+            }
+        } else if let Some(token) = token {
+            let claims = match request.state().keys.verify::<Claims>(&token) {
+                Ok(claims) => claims,
+                Err(_) => return invalid_token(),
+            };
+            match request.state().users().get_by_id(&claims.uid).await {
+                Ok(user) => {
+                    request.set_ext(user);
+                    request.set_ext(claims);
+                }
+                Err(_) => return invalid_token(),
+            }
+        }
+
+        Ok(next.run(request).await)
+    }
+}
+
+fn invalid_token() -> tide::Result {
+    let mut res = tide::Result::from(ApiError::unauthorized("invalid or expired token"))?;
+    res.insert_header("WWW-Authenticate", r#"Bearer error="invalid_token""#);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde::{Deserialize, Serialize};
+
+    use super::Keyring;
+    use crate::config::Config;
+    use crate::timestamp;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Token {
+        sub: String,
+        exp: usize,
+    }
+
+    fn token(ttl: i64) -> Token {
+        Token {
+            sub: String::from("42"),
+            exp: (timestamp() as i64 + ttl) as usize,
+        }
+    }
+
+    fn keyring(keys: &str, kid: Option<&str>) -> Result<Keyring, String> {
+        Keyring::from_config(&Config {
+            jwt_keys: Some(keys.to_string()),
+            jwt_kid: kid.map(String::from),
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn signs_and_verifies_with_the_active_key() {
+        let keys = keyring("old:HS256:one,new:HS256:two", Some("new")).unwrap();
+        let signed = keys.sign(&token(60)).unwrap();
+        let header = jsonwebtoken::decode_header(&signed).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("new"));
+        assert_eq!(header.alg, Algorithm::HS256);
+        assert_eq!(keys.verify::<Token>(&signed).unwrap(), token(60));
+
+        // tokens signed before a rotation stay valid while the key is kept
+        let rotated = keyring("old:HS256:one,new:HS256:two", Some("old")).unwrap();
+        assert!(rotated.verify::<Token>(&signed).is_ok());
+    }
+
+    #[test]
+    fn keeps_colons_in_hs256_secrets() {
+        let keys = keyring("a:HS256:se:cr:et", None).unwrap();
+        let signed = jsonwebtoken::encode(
+            &Header {
+                kid: Some(String::from("a")),
+                ..Header::new(Algorithm::HS256)
+            },
+            &token(60),
+            &EncodingKey::from_secret(b"se:cr:et"),
+        )
+        .unwrap();
+        assert!(keys.verify::<Token>(&signed).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_key_specs() {
+        assert!(keyring("a:RS256:secret", None).is_err());
+        assert!(keyring("a:EdDSA:private.pem", None).is_err());
+        assert!(keyring("a:HS256:secret", Some("b")).is_err());
+        assert!(keyring(" , ", None).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_kids_and_mismatched_algorithms() {
+        let keys = keyring("a:HS256:secret", None).unwrap();
+        let other = keyring("b:HS256:secret", None).unwrap();
+        assert!(keys
+            .verify::<Token>(&other.sign(&token(60)).unwrap())
+            .is_err());
+
+        let sign = |header: Header| {
+            jsonwebtoken::encode(&header, &token(60), &EncodingKey::from_secret(b"secret")).unwrap()
+        };
+        let without_kid = sign(Header::new(Algorithm::HS256));
+        assert!(keys.verify::<Token>(&without_kid).is_err());
+        let other_alg = sign(Header {
+            kid: Some(String::from("a")),
+            ..Header::new(Algorithm::HS512)
+        });
+        assert!(keys.verify::<Token>(&other_alg).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let keys = keyring("a:HS256:secret", None).unwrap();
+        // past the leeway jsonwebtoken allows for clock skew
+        let expired = keys.sign(&token(-120)).unwrap();
+        assert!(keys.verify::<Token>(&expired).is_err());
+    }
+}
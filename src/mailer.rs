@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use lettre::{
+    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncStd1Executor,
+    AsyncTransport, Message,
+};
+
+use crate::config::Config;
+use crate::timestamp;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync + 'static {
+    async fn send(&self, email: Email) -> tide::Result<()>;
+}
+
+pub struct SmtpMailer {
+    from: String,
+    transport: AsyncSmtpTransport<AsyncStd1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        username: String,
+        password: String,
+        from: String,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let transport = AsyncSmtpTransport::<AsyncStd1Executor>::relay(host)?
+            .credentials(Credentials::new(username, password))
+            .build();
+        Ok(SmtpMailer { from, transport })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> tide::Result<()> {
+        let message = Message::builder()
+            .from(self.from.parse()?)
+            .to(email.to.parse()?)
+            .subject(email.subject)
+            .body(email.body)?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes every email to a file in `dir` instead of sending it, for local
+/// development and tests.
+pub struct OutboxMailer {
+    dir: PathBuf,
+}
+
+impl OutboxMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        OutboxMailer { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for OutboxMailer {
+    async fn send(&self, email: Email) -> tide::Result<()> {
+        async_std::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            timestamp(),
+            uuid::Uuid::new_v4().simple()
+        ));
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        async_std::fs::write(path, contents).await?;
+        Ok(())
+    }
+}
+
+/// Picks the mailer from `mailer`, either `smtp` (configured through
+/// `smtp_host`, `smtp_username`, `smtp_password` and `mail_from`) or
+/// `outbox`, which writes to `outbox_dir`.
+pub fn from_config(config: &Config) -> Result<Box<dyn Mailer>, lettre::transport::smtp::Error> {
+    match (config.mailer.as_str(), &config.smtp_host, &config.mail_from) {
+        ("smtp", Some(host), Some(from)) => Ok(Box::new(SmtpMailer::new(
+            host,
+            config.smtp_username.clone().unwrap_or_default(),
+            config.smtp_password.clone().unwrap_or_default(),
+            from.clone(),
+        )?)),
+        // anything else has been rejected when loading the config
+        _ => Ok(Box::new(OutboxMailer::new(&config.outbox_dir))),
+    }
+}
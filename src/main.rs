@@ -1,18 +1,36 @@
-#![feature(local_key_cell_methods)]
-use std::{future::Future, pin::Pin, time::Duration};
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use async_redis_session::RedisSessionStore;
+use config::{Cli, Config};
 use mongodb::{options::ClientOptions, Client};
 use registry::State;
+use repos::user::User;
 use serde::{Deserialize, Serialize};
+use sessions::{SessionBackend, SessionMiddleware};
 use tide::log::LogMiddleware;
 use tide_flash::{cookies::CookieStore, FlashMiddleware};
 
+mod config;
+mod csrf;
+mod error;
+mod forms;
+mod helpers;
+mod jobs;
+mod json;
+mod jwt;
+mod mailer;
+mod oidc;
+mod policy;
+mod ratelimit;
 mod registry;
 mod repos;
 mod request_ext;
 mod route_ext;
 mod routes;
+mod sessions;
 mod templates;
 
 mod prelude {
@@ -21,7 +39,20 @@ mod prelude {
     pub use tide_flash::ext::*;
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How long a session stays elevated after re-entering the password.
+pub const SUDO_TTL: Duration = Duration::from_secs(60 * 10);
+
+/// Url of the request as it arrived, before nested routers stripped their
+/// prefix from the path.
+#[derive(Debug, Clone)]
+pub struct OriginalUrl(pub tide::http::Url);
+
+/// Id generated for each request, sent back as `X-Request-Id` and recorded
+/// with audit events so they can be matched to the logs.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     sub: String,
     username: String,
@@ -32,6 +63,31 @@ pub struct Claims {
     totp: Option<usize>,
 }
 
+impl Claims {
+    pub fn new(user: &User, ttl: Duration) -> Self {
+        Claims {
+            sub: user.username.clone(),
+            username: user.username.clone(),
+            uid: user._id.clone(),
+            exp: timestamp() + ttl.as_secs() as usize,
+            totp_enabled: user.totp_enabled,
+            totp_attempt: 0,
+            totp: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.exp <= timestamp()
+    }
+}
+
+/// Seconds since the unix epoch.
+pub fn timestamp() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as usize)
+}
+
 fn no_store<'a>(
     req: tide::Request<State>,
     next: tide::Next<'a, State>,
@@ -40,7 +96,7 @@ fn no_store<'a>(
     Box::pin(async {
         let mut res = next.run(req).await;
 
-        if let None = res.header("Cache-Control") {
+        if res.header("Cache-Control").is_none() {
             let mut header = CacheControl::new();
             header.push(CacheDirective::NoStore);
             header.push(CacheDirective::MaxAge(Duration::from_secs(0)));
@@ -51,36 +107,84 @@ fn no_store<'a>(
     })
 }
 
+fn request_id<'a>(
+    mut req: tide::Request<State>,
+    next: tide::Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async {
+        let id = uuid::Uuid::new_v4().to_string();
+        req.set_ext(RequestId(id.clone()));
+        let mut res = next.run(req).await;
+        res.insert_header("X-Request-Id", id);
+        Ok(res)
+    })
+}
+
+fn original_url<'a>(
+    mut req: tide::Request<State>,
+    next: tide::Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async {
+        let url = req.url().clone();
+        req.set_ext(OriginalUrl(url));
+        Ok(next.run(req).await)
+    })
+}
+
 #[async_std::main]
 async fn main() -> tide::Result<()> {
+    // .env has to be loaded before anything reads the environment
+    dotenv::dotenv().ok();
+    let cli = Cli::parse(std::env::args().skip(1)).unwrap_or_else(|e| exit(&[e]));
+    let config = Config::load(&cli).unwrap_or_else(|problems| exit(&problems));
+    if cli.print_config {
+        print!("{}", toml::to_string(&config.redacted())?);
+        return Ok(());
+    }
+    tide::log::start();
+
     // configure mongodb client options
-    let mongodb_url = std::env::var("MONGODB_URI")?;
-    let app_name = std::env::var("APP_NAME")?;
-    let mut client_options = ClientOptions::parse(mongodb_url).await?;
-    client_options.app_name = Some(app_name);
+    let mut client_options = ClientOptions::parse(&config.mongodb_uri).await?;
+    client_options.app_name = Some(config.app_name.clone());
     let client = Client::with_options(client_options)?;
 
+    // the session store is shared with the state to revoke other sessions
+    let db = client.database(&config.db_name);
+    let session_store = SessionBackend::from_config(&config, &db).await?;
+
     // setup tide app with client
-    let mut app = tide::with_state(State::new(client));
-    dotenv::dotenv().ok();
-    tide::log::start();
+    let state = State::new(client, session_store.clone(), config)?;
+    state.ensure_indexes().await?;
+    state.promote_admins().await?;
+    jobs::spawn(state.clone());
+    let config = state.config.clone();
+    let mut app = tide::with_state(state);
 
     app.with(no_store);
+    app.with(request_id);
+    app.with(original_url);
     app.with(LogMiddleware::new());
+    app.with(error::ErrorMiddleware {});
 
-    // configure openid connect and session middleware
-    let session_secret = std::env::var("SESSION_SECRET")?;
-    let redis_url = std::env::var("REDIS_URL")?;
-    app.with(tide::sessions::SessionMiddleware::new(
-        RedisSessionStore::new(redis_url)?,
-        session_secret.as_bytes(),
-    ));
+    // configure session middleware
+    app.with(SessionMiddleware::new(session_store, &config));
     app.with(FlashMiddleware::new(CookieStore::default()));
+    app.with(jwt::BearerMiddleware {});
+    app.with(csrf::CsrfMiddleware {});
     routes::configure(&mut app);
 
-    let host = std::env::var("HOST").unwrap_or(String::from("0.0.0.0"));
-    let port: u16 = std::env::var("PORT")?.parse()?;
-    app.listen((host, port)).await?;
+    templates::with_json_suffix(app)
+        .listen((config.host.as_str(), config.port))
+        .await?;
 
     Ok(())
 }
+
+/// Reports configuration problems and stops before anything is started.
+fn exit(problems: &[String]) -> ! {
+    eprintln!("invalid configuration:");
+    for problem in problems {
+        eprintln!("  - {}", problem);
+    }
+    std::process::exit(2)
+}
@@ -0,0 +1,132 @@
+//! Authorization rules. Handlers ask these instead of comparing ids and roles
+//! themselves, so who may do what is decided in one place.
+
+use crate::repos::oauth::OAuthApp;
+use crate::repos::post::Post;
+use crate::repos::user::{Role, User};
+
+/// Something that belongs to a single user and can be managed by admins as
+/// well. Tokens, sessions, grants and linked identities are only ever looked
+/// up by their owner's id, so they don't need it.
+pub trait Owned {
+    fn owner(&self) -> &str;
+}
+
+impl Owned for OAuthApp {
+    fn owner(&self) -> &str {
+        &self.owner
+    }
+}
+
+impl Owned for Post {
+    fn owner(&self) -> &str {
+        &self.uid
+    }
+}
+
+pub fn has_role(user: &User, role: Role) -> bool {
+    user.role >= role
+}
+
+pub fn is_owner<T: Owned>(user: &User, resource: &T) -> bool {
+    resource.owner() == user._id
+}
+
+/// Whether `user` can change or delete `resource`. Admins can manage
+/// anything, everyone else only what they own.
+pub fn can_manage<T: Owned>(user: &User, resource: &T) -> bool {
+    is_owner(user, resource) || has_role(user, Role::Admin)
+}
+
+/// Whether `user` can suspend, reset or change the role of `target`'s
+/// account. Admins can't act on their own account, so they can't lock
+/// themselves out.
+pub fn can_administer(user: &User, target: &User) -> bool {
+    has_role(user, Role::Admin) && user._id != target._id
+}
+
+pub fn can_moderate(user: &User) -> bool {
+    has_role(user, Role::Moderator)
+}
+
+/// Whether `user` can warn or suspend `target` while resolving a report.
+/// Moderators can only act on accounts with a lower role than their own.
+pub fn can_sanction(user: &User, target: &User) -> bool {
+    can_moderate(user) && target.role < user.role
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str, role: Role) -> User {
+        // hashing a password for every user would slow the tests down
+        static USER: std::sync::OnceLock<User> = std::sync::OnceLock::new();
+        let mut user = USER
+            .get_or_init(|| User::new(String::from("a@example.com"), "correct horse").unwrap())
+            .clone();
+        user._id = id.to_string();
+        user.role = role;
+        user
+    }
+
+    const ROLES: [Role; 3] = [Role::User, Role::Moderator, Role::Admin];
+
+    #[test]
+    fn roles_include_the_ones_below_them() {
+        for (i, held) in ROLES.into_iter().enumerate() {
+            for (j, required) in ROLES.into_iter().enumerate() {
+                assert_eq!(has_role(&user("a", held), required), i >= j);
+            }
+        }
+    }
+
+    #[test]
+    fn only_owners_and_admins_manage_resources() {
+        let post = Post::new("owner", "hi", None);
+        assert!(can_manage(&user("owner", Role::User), &post));
+        assert!(!can_manage(&user("other", Role::User), &post));
+        assert!(!can_manage(&user("other", Role::Moderator), &post));
+        assert!(can_manage(&user("other", Role::Admin), &post));
+        assert!(is_owner(&user("owner", Role::User), &post));
+        assert!(!is_owner(&user("other", Role::Admin), &post));
+    }
+
+    #[test]
+    fn admins_administer_everyone_but_themselves() {
+        for role in ROLES {
+            let target = user("target", role);
+            assert!(can_administer(&user("admin", Role::Admin), &target));
+            assert!(!can_administer(&user("mod", Role::Moderator), &target));
+            assert!(!can_administer(&user("user", Role::User), &target));
+        }
+        let admin = user("admin", Role::Admin);
+        assert!(!can_administer(&admin, &admin));
+    }
+
+    #[test]
+    fn moderators_sanction_only_lower_roles() {
+        // (moderator, target, allowed)
+        let matrix = [
+            (Role::User, Role::User, false),
+            (Role::Moderator, Role::User, true),
+            (Role::Moderator, Role::Moderator, false),
+            (Role::Moderator, Role::Admin, false),
+            (Role::Admin, Role::User, true),
+            (Role::Admin, Role::Moderator, true),
+            (Role::Admin, Role::Admin, false),
+        ];
+        for (acting, target, allowed) in matrix {
+            assert_eq!(
+                can_sanction(&user("a", acting), &user("b", target)),
+                allowed,
+                "{:?} acting on {:?}",
+                acting,
+                target
+            );
+        }
+        assert!(!can_moderate(&user("a", Role::User)));
+        assert!(can_moderate(&user("a", Role::Moderator)));
+        assert!(can_moderate(&user("a", Role::Admin)));
+    }
+}
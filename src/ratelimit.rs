@@ -0,0 +1,409 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tide::{http::Method, Body, Middleware, Next, Redirect, Request};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::json::ApiError;
+use crate::prelude::*;
+use crate::registry::State;
+use crate::timestamp;
+
+/// Token bucket settings for one kind of key.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+    /// Attempts made with an empty bucket before the key is locked out.
+    pub max_strikes: u32,
+    /// Delay for the first strike, doubled for every strike after it.
+    pub base_delay: Duration,
+    pub lockout: Duration,
+}
+
+impl Policy {
+    pub const IP: Policy = Policy {
+        capacity: 20.0,
+        refill_per_sec: 1.0 / 6.0,
+        max_strikes: 10,
+        base_delay: Duration::from_millis(250),
+        lockout: Duration::from_secs(60 * 15),
+    };
+
+    pub const USERNAME: Policy = Policy {
+        capacity: 5.0,
+        refill_per_sec: 1.0 / 60.0,
+        max_strikes: 5,
+        base_delay: Duration::from_millis(500),
+        lockout: Duration::from_secs(60 * 15),
+    };
+
+    /// How long a bucket has to be kept: until a lockout is over and the
+    /// bucket has refilled after it.
+    fn ttl(&self) -> Duration {
+        self.lockout + Duration::from_secs_f64(self.capacity / self.refill_per_sec)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: usize,
+    pub strikes: u32,
+    pub locked_until: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Allowed,
+    Delayed(Duration),
+    /// The key was locked out by this attempt.
+    LockedOut(usize),
+    /// The key is still locked out from an earlier attempt.
+    Locked(usize),
+}
+
+impl Bucket {
+    fn new(policy: &Policy, now: usize) -> Self {
+        Bucket {
+            tokens: policy.capacity,
+            updated_at: now,
+            strikes: 0,
+            locked_until: None,
+        }
+    }
+
+    fn hit(&mut self, policy: &Policy, now: usize) -> Outcome {
+        match self.locked_until {
+            Some(until) if until > now => return Outcome::Locked(until),
+            Some(_) => *self = Bucket::new(policy, now),
+            None => {}
+        }
+
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens = (self.tokens + elapsed * policy.refill_per_sec).min(policy.capacity);
+        self.updated_at = now;
+        if self.tokens >= policy.capacity {
+            self.strikes = 0;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Outcome::Allowed;
+        }
+
+        self.strikes += 1;
+        if self.strikes >= policy.max_strikes {
+            let until = now + policy.lockout.as_secs() as usize;
+            self.locked_until = Some(until);
+            Outcome::LockedOut(until)
+        } else {
+            Outcome::Delayed(policy.base_delay * 2u32.pow((self.strikes - 1).min(8)))
+        }
+    }
+}
+
+/// Backend persisting bucket state between requests. Attempts on the same
+/// key have to be applied one after the other, otherwise parallel requests
+/// all see the same count and get past the delay and the lockout.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Records an attempt on the bucket of `key` and returns its outcome.
+    async fn hit(&self, key: &str, policy: &Policy, now: usize) -> tide::Result<Outcome>;
+}
+
+/// Keeps buckets in memory along with when they expire, expired ones are
+/// dropped on every attempt.
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<HashMap<String, (Bucket, usize)>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn hit(&self, key: &str, policy: &Policy, now: usize) -> tide::Result<Outcome> {
+        // held until the bucket is updated
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, (_, expires_at)| *expires_at > now);
+        let (bucket, expires_at) = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| (Bucket::new(policy, now), now));
+        *expires_at = now + policy.ttl().as_secs() as usize;
+        Ok(bucket.hit(policy, now))
+    }
+}
+
+pub struct RedisRateLimitStore {
+    client: redis::Client,
+}
+
+impl RedisRateLimitStore {
+    pub fn new(redis_url: &str) -> redis::RedisResult<Self> {
+        Ok(RedisRateLimitStore {
+            client: redis::Client::open(redis_url)?,
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisRateLimitStore {
+    /// Updates the bucket in a WATCH/MULTI transaction, which redis aborts
+    /// when another attempt saved the bucket in between. The attempt is then
+    /// made again on the newer bucket.
+    async fn hit(&self, key: &str, policy: &Policy, now: usize) -> tide::Result<Outcome> {
+        let key = format!("ratelimit:{}", key);
+        let mut connection = self.client.get_async_std_connection().await?;
+        loop {
+            let _: () = redis::cmd("WATCH")
+                .arg(&key)
+                .query_async(&mut connection)
+                .await?;
+            let value: Option<String> = connection.get(&key).await?;
+            let mut bucket = match value {
+                Some(value) => serde_json::from_str(&value)?,
+                None => Bucket::new(policy, now),
+            };
+            let outcome = bucket.hit(policy, now);
+            let saved: Option<()> = redis::pipe()
+                .atomic()
+                .set_ex(
+                    &key,
+                    serde_json::to_string(&bucket)?,
+                    policy.ttl().as_secs() as usize,
+                )
+                .ignore()
+                .query_async(&mut connection)
+                .await?;
+            if saved.is_some() {
+                return Ok(outcome);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(store: impl RateLimitStore) -> Self {
+        RateLimiter {
+            store: Arc::new(store),
+        }
+    }
+
+    /// Keeps counters in memory when `rate_limit_store` is `memory` or there
+    /// is no `redis_url`, in redis otherwise.
+    pub fn from_config(config: &Config) -> redis::RedisResult<Self> {
+        match (config.rate_limit_store.as_deref(), &config.redis_url) {
+            (Some("memory"), _) | (_, None) => {
+                Ok(RateLimiter::new(MemoryRateLimitStore::default()))
+            }
+            (_, Some(url)) => Ok(RateLimiter::new(RedisRateLimitStore::new(url)?)),
+        }
+    }
+
+    pub async fn hit(&self, key: &str, policy: &Policy) -> tide::Result<Outcome> {
+        self.store.hit(key, policy, timestamp()).await
+    }
+}
+
+/// Record of a key being locked out, kept for admins to review.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lockout {
+    pub _id: String,
+    pub kind: String,
+    pub value: String,
+    pub created_at: usize,
+    pub locked_until: usize,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: Option<String>,
+}
+
+/// Throttles password attempts per client ip and per username. With
+/// `by_account` the attempts are counted against the logged in user instead,
+/// for routes where they confirm their password again, so a stolen session
+/// can't be used to guess it.
+pub struct RateLimitMiddleware {
+    pub by_account: bool,
+}
+
+impl RateLimitMiddleware {
+    async fn username(request: &mut Request<State>) -> tide::Result<Option<String>> {
+        let bytes = request.body_bytes().await?;
+        let is_json = request
+            .content_type()
+            .is_some_and(|mime| mime.subtype() == "json");
+        let credentials = if is_json {
+            Body::from_bytes(bytes.clone())
+                .into_json::<Credentials>()
+                .await
+        } else {
+            Body::from_bytes(bytes.clone())
+                .into_form::<Credentials>()
+                .await
+        };
+        request.set_body(bytes);
+        Ok(credentials
+            .ok()
+            .and_then(|c| c.username)
+            .map(|u| u.trim().to_lowercase()))
+    }
+}
+
+#[async_trait]
+impl Middleware<State> for RateLimitMiddleware {
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if request.method() != Method::Post {
+            return Ok(next.run(request).await);
+        }
+
+        let ip = request.client_ip().unwrap_or_default();
+        let mut keys = vec![("ip", ip, Policy::IP)];
+        if self.by_account {
+            if let Some(user) = request.user() {
+                keys.push(("uid", user._id.clone(), Policy::USERNAME));
+            }
+        } else if let Some(username) = RateLimitMiddleware::username(&mut request).await? {
+            keys.push(("username", username, Policy::USERNAME));
+        }
+
+        let limiter = request.state().limiter.clone();
+        let mut delay = Duration::ZERO;
+        for (kind, value, policy) in keys {
+            match limiter.hit(&format!("{}:{}", kind, value), &policy).await? {
+                Outcome::Allowed => {}
+                Outcome::Delayed(d) => delay = delay.max(d),
+                Outcome::Locked(until) => return locked_out(&request, until),
+                Outcome::LockedOut(until) => {
+                    let lockout = Lockout {
+                        _id: Uuid::new_v4().to_string(),
+                        kind: kind.to_string(),
+                        value,
+                        created_at: timestamp(),
+                        locked_until: until,
+                    };
+                    tide::log::warn!("locked out {} {} until {}", kind, lockout.value, until);
+                    request
+                        .state()
+                        .lockouts()
+                        .insert_one(&lockout, None)
+                        .await?;
+                    return locked_out(&request, until);
+                }
+            }
+        }
+
+        if !delay.is_zero() {
+            async_std::task::sleep(delay).await;
+        }
+        Ok(next.run(request).await)
+    }
+}
+
+fn locked_out(request: &Request<State>, until: usize) -> tide::Result {
+    let retry_after = until.saturating_sub(timestamp()).to_string();
+    let mut res = if request.is_api() {
+        tide::Result::from(ApiError::too_many_requests())?
+    } else {
+        let mut res: tide::Response = Redirect::new("/").into();
+        res.flash_error("too many failed attempts, try again later");
+        res
+    };
+    res.insert_header("Retry-After", retry_after);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Bucket, MemoryRateLimitStore, Outcome, Policy, RateLimitStore};
+
+    const POLICY: Policy = Policy {
+        capacity: 3.0,
+        refill_per_sec: 0.1,
+        max_strikes: 3,
+        base_delay: Duration::from_millis(100),
+        lockout: Duration::from_secs(60),
+    };
+
+    #[test]
+    fn allows_the_capacity_then_doubles_the_delay_until_locked_out() {
+        let mut bucket = Bucket::new(&POLICY, 0);
+        for _ in 0..3 {
+            assert_eq!(bucket.hit(&POLICY, 0), Outcome::Allowed);
+        }
+        assert_eq!(
+            bucket.hit(&POLICY, 0),
+            Outcome::Delayed(Duration::from_millis(100))
+        );
+        assert_eq!(
+            bucket.hit(&POLICY, 0),
+            Outcome::Delayed(Duration::from_millis(200))
+        );
+        assert_eq!(bucket.hit(&POLICY, 0), Outcome::LockedOut(60));
+        assert_eq!(bucket.hit(&POLICY, 30), Outcome::Locked(60));
+
+        // a full bucket once the lockout is over
+        for _ in 0..3 {
+            assert_eq!(bucket.hit(&POLICY, 60), Outcome::Allowed);
+        }
+        assert!(matches!(bucket.hit(&POLICY, 60), Outcome::Delayed(_)));
+    }
+
+    #[test]
+    fn refills_over_time_and_forgets_strikes_once_full() {
+        let mut bucket = Bucket::new(&POLICY, 0);
+        for _ in 0..3 {
+            bucket.hit(&POLICY, 0);
+        }
+        assert!(matches!(bucket.hit(&POLICY, 0), Outcome::Delayed(_)));
+        assert_eq!(bucket.hit(&POLICY, 10), Outcome::Allowed);
+        assert!(matches!(bucket.hit(&POLICY, 10), Outcome::Delayed(_)));
+        assert_eq!(bucket.strikes, 2);
+
+        assert_eq!(bucket.hit(&POLICY, 1000), Outcome::Allowed);
+        assert_eq!(bucket.strikes, 0);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+
+    #[async_std::test]
+    async fn memory_store_drops_expired_buckets() {
+        let store = MemoryRateLimitStore::default();
+        store.hit("ip:a", &POLICY, 0).await.unwrap();
+        let expiry = POLICY.ttl().as_secs() as usize;
+        store.hit("ip:b", &POLICY, expiry).await.unwrap();
+        assert_eq!(store.buckets.lock().await.len(), 1);
+        assert!(store.buckets.lock().await.contains_key("ip:b"));
+    }
+
+    #[async_std::test]
+    async fn memory_store_applies_parallel_attempts_one_by_one() {
+        let store = Arc::new(MemoryRateLimitStore::default());
+        let attempts: Vec<_> = (0..10)
+            .map(|_| {
+                let store = store.clone();
+                async_std::task::spawn(async move { store.hit("ip:a", &POLICY, 0).await })
+            })
+            .collect();
+        let mut allowed = 0;
+        for attempt in attempts {
+            if attempt.await.unwrap() == Outcome::Allowed {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 3);
+        let buckets = store.buckets.lock().await;
+        assert!(buckets["ip:a"].0.locked_until.is_some());
+    }
+}
@@ -1,40 +1,109 @@
+use std::sync::Arc;
+
 use handlebars::Handlebars;
 use mongodb::{Client, Collection};
 use serde::Serialize;
+use tide::sessions::{Session, SessionStore};
 
-use crate::repos::user::User;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::helpers;
+use crate::jwt::Keyring;
+use crate::mailer::{self, Mailer};
+use crate::oidc::Oidc;
+use crate::ratelimit::{Lockout, RateLimiter};
+use crate::repos::audit::AuditEvent;
+use crate::repos::follow::Follow;
+use crate::repos::identity::Identity;
+use crate::repos::job::Job;
+use crate::repos::notification::Notification;
+use crate::repos::oauth::{AuthorizationCode, Grant, OAuthApp};
+use crate::repos::post::Post;
+use crate::repos::report::{Report, ReportRepository};
+use crate::repos::session::{SessionRecord, SessionRepository};
+use crate::repos::token::{AccessToken, ConsumedToken, RefreshToken};
+use crate::repos::user::{Role, User, UserRepository};
+use crate::sessions::SessionBackend;
 
 #[derive(Clone)]
 pub struct State {
     pub registry: Handlebars<'static>,
     pub client: Client,
-    db_name: String,
+    pub keys: Keyring,
+    pub limiter: RateLimiter,
+    pub mailer: Arc<dyn Mailer>,
+    pub oidc: Oidc,
+    /// Backing store of the session middleware, used to end sessions other
+    /// than the one making the request.
+    pub session_store: SessionBackend,
+    pub config: Arc<Config>,
 }
 
 impl State {
-    pub fn new(client: Client) -> State {
-        let db_name = std::env::var("DB_NAME").expect("Must specify a database name");
+    pub fn new(
+        client: Client,
+        session_store: SessionBackend,
+        config: Config,
+    ) -> Result<State, AppError> {
+        let limiter =
+            RateLimiter::from_config(&config).map_err(|e| AppError::Internal(e.to_string()))?;
+        let mailer = mailer::from_config(&config).map_err(|e| AppError::Internal(e.to_string()))?;
         let mut state = State {
             registry: Handlebars::new(),
             client,
-            db_name,
+            keys: Keyring::from_config(&config).map_err(AppError::Internal)?,
+            limiter,
+            mailer: Arc::from(mailer),
+            session_store,
+            oidc: Oidc::from_config(&config),
+            config: Arc::new(config),
         };
-        state.register_template("index.html", "static/index.html");
-        state.register_template("login.html", "static/login.html");
-        state.register_template("otp.html", "static/otp.html");
-        state.register_template("2fa.html", "static/2fa.html");
-        state.register_template("settings.html", "static/settings.html");
-        state.register_template("register.html", "static/register.html");
+        state.register_template("index.html", "static/index.html")?;
+        state.register_template("404.html", "static/404.html")?;
+        state.register_template("500.html", "static/500.html")?;
+        state.register_template("login.html", "static/login.html")?;
+        state.register_template("otp.html", "static/otp.html")?;
+        state.register_template("2fa.html", "static/2fa.html")?;
+        state.register_template("settings.html", "static/settings.html")?;
+        state.register_template("register.html", "static/register.html")?;
+        state.register_template("recovery.html", "static/recovery.html")?;
+        state.register_template("sudo.html", "static/sudo.html")?;
+        state.register_template("sessions.html", "static/sessions.html")?;
+        state.register_template("security.html", "static/security.html")?;
+        state.register_template("identities.html", "static/identities.html")?;
+        state.register_template("apps.html", "static/apps.html")?;
+        state.register_template("consent.html", "static/consent.html")?;
+        state.register_template("forgot-password.html", "static/forgot-password.html")?;
+        state.register_template("reset-password.html", "static/reset-password.html")?;
+        state.register_template("report.html", "static/report.html")?;
+        state.register_template("admin/users.html", "static/admin/users.html")?;
+        state.register_template("admin/user.html", "static/admin/user.html")?;
+        state.register_template("admin/audit.html", "static/admin/audit.html")?;
+        state.register_template("admin/lockouts.html", "static/admin/lockouts.html")?;
+        state.register_template("admin/reports.html", "static/admin/reports.html")?;
+        state.register_template("admin/report.html", "static/admin/report.html")?;
+        state.register_template("verify-email.txt", "static/email/verify-email.txt")?;
+        state.register_template("reset-password.txt", "static/email/reset-password.txt")?;
+        state.register_template("report-outcome.txt", "static/email/report-outcome.txt")?;
+        state.register_template("account-warning.txt", "static/email/account-warning.txt")?;
+        state.register_template("export-ready.txt", "static/email/export-ready.txt")?;
+        state
+            .registry
+            .register_helper("format", Box::new(helpers::format_helper));
         state
+            .registry
+            .register_helper("csrf_field", Box::new(helpers::csrf_field_helper));
+        Ok(state)
     }
 
-    pub fn register_template(&mut self, name: &str, path: &str) {
-        self.registry.register_template_file(name, path).unwrap();
+    pub fn register_template(&mut self, name: &str, path: &str) -> Result<(), AppError> {
+        self.registry.register_template_file(name, path)?;
+        Ok(())
     }
 
     pub fn db<T: Serialize>(&self, collection_name: &str) -> Collection<T> {
         self.client
-            .database(&self.db_name)
+            .database(&self.config.db_name)
             .collection::<T>(collection_name)
     }
 
@@ -42,6 +111,117 @@ impl State {
         self.db::<User>("users")
     }
 
+    pub fn refresh_tokens(&self) -> Collection<RefreshToken> {
+        self.db::<RefreshToken>("refresh_tokens")
+    }
+
+    pub fn access_tokens(&self) -> Collection<AccessToken> {
+        self.db::<AccessToken>("access_tokens")
+    }
+
+    pub fn lockouts(&self) -> Collection<Lockout> {
+        self.db::<Lockout>("lockouts")
+    }
+
+    pub fn consumed_tokens(&self) -> Collection<ConsumedToken> {
+        self.db::<ConsumedToken>("consumed_tokens")
+    }
+
+    pub fn identities(&self) -> Collection<Identity> {
+        self.db::<Identity>("identities")
+    }
+
+    pub fn oauth_apps(&self) -> Collection<OAuthApp> {
+        self.db::<OAuthApp>("oauth_apps")
+    }
+
+    pub fn authorization_codes(&self) -> Collection<AuthorizationCode> {
+        self.db::<AuthorizationCode>("authorization_codes")
+    }
+
+    pub fn grants(&self) -> Collection<Grant> {
+        self.db::<Grant>("grants")
+    }
+
+    pub fn sessions(&self) -> Collection<SessionRecord> {
+        self.db::<SessionRecord>("sessions")
+    }
+
+    pub fn reports(&self) -> Collection<Report> {
+        self.db::<Report>("reports")
+    }
+
+    pub fn audit_events(&self) -> Collection<AuditEvent> {
+        self.db::<AuditEvent>("audit_events")
+    }
+
+    pub fn jobs(&self) -> Collection<Job> {
+        self.db::<Job>("jobs")
+    }
+
+    pub fn posts(&self) -> Collection<Post> {
+        self.db::<Post>("posts")
+    }
+
+    pub fn follows(&self) -> Collection<Follow> {
+        self.db::<Follow>("follows")
+    }
+
+    pub fn notifications(&self) -> Collection<Notification> {
+        self.db::<Notification>("notifications")
+    }
+
+    /// Removes the session from the store so its cookie stops working, and
+    /// drops it from the index.
+    pub async fn destroy_session(&self, record: &SessionRecord) -> tide::Result<()> {
+        let session: Session = serde_json::from_value(serde_json::json!({
+            "id": record.session_id,
+            "expiry": null,
+            "data": {},
+        }))?;
+        self.session_store.destroy_session(session).await?;
+        self.sessions().remove(&record._id).await?;
+        Ok(())
+    }
+
+    /// Destroys every session of `uid`, except the one with `keep` as its
+    /// session store id when given.
+    pub async fn destroy_sessions(&self, uid: &str, keep: Option<&str>) -> tide::Result<()> {
+        for record in self.sessions().list(uid).await? {
+            if keep != Some(record.session_id.as_str()) {
+                self.destroy_session(&record).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the indexes the repositories rely on, where they are missing.
+    pub async fn ensure_indexes(&self) -> tide::Result<()> {
+        self.reports().ensure_indexes().await?;
+        Ok(())
+    }
+
+    /// Gives the admin role to the accounts listed in `admin_usernames`, so
+    /// there is someone to hand out roles on a fresh install. Only accounts
+    /// that verified their email are promoted, anyone could otherwise claim
+    /// a listed username before its owner registers.
+    pub async fn promote_admins(&self) -> tide::Result<()> {
+        for username in &self.config.admin_usernames {
+            match self.users().get_by_username(username).await {
+                Ok(user) if user.role != Role::Admin && !user.email_verified => {
+                    tide::log::warn!("admin {} has not verified their email yet", username)
+                }
+                Ok(mut user) if user.role != Role::Admin => {
+                    user.role = Role::Admin;
+                    self.users().update(user).await?;
+                }
+                Ok(_) => {}
+                Err(_) => tide::log::warn!("admin {} has not registered yet", username),
+            }
+        }
+        Ok(())
+    }
+
     pub fn render<T: Serialize>(
         &self,
         name: &str,
@@ -50,3 +230,27 @@ impl State {
         self.registry.render(name, data)
     }
 }
+
+#[cfg(test)]
+impl State {
+    /// State for tests that don't need the database. The client points at a
+    /// closed port and gives up quickly, queries fail instead of hanging.
+    pub fn for_tests() -> State {
+        use mongodb::options::{ClientOptions, ServerAddress};
+
+        let options = ClientOptions::builder()
+            .hosts(vec![ServerAddress::Tcp {
+                host: String::from("127.0.0.1"),
+                port: Some(1),
+            }])
+            .server_selection_timeout(std::time::Duration::from_millis(100))
+            .build();
+        let config = Config {
+            db_name: String::from("test"),
+            session_secret: String::from("test-secret-test-secret-test-secret"),
+            ..Config::default()
+        };
+        let store = SessionBackend::Memory(async_session::MemoryStore::new());
+        State::new(Client::with_options(options).unwrap(), store, config).unwrap()
+    }
+}
@@ -1,53 +1,29 @@
 use std::io::Error;
 
+pub mod audit;
+pub mod follow;
+pub mod identity;
+pub mod job;
+pub mod notification;
+pub mod oauth;
+pub mod post;
+pub mod report;
+pub mod session;
+pub mod token;
 pub mod user;
 
-pub trait Store<Id, T> {
-    fn insert(&mut self, item: T) -> Result<T, Error>;
-    fn update(&mut self, id: Id, item: T) -> Result<T, Error>;
-    fn get_by_id(&self, id: Id) -> Result<T, Error>;
-    fn list(&self) -> Result<Vec<T>, Error>;
+pub fn db_error(e: mongodb::error::Error) -> Error {
+    Error::other(e)
 }
 
-pub trait UniqueId<T> {
-    fn get_id<'item>(&'item self) -> Option<&'item T>;
-}
-
-#[derive(Debug, Clone)]
-pub struct MemoryStore<T> {
-    pub cache: Vec<T>,
-}
-
-impl<T: Clone + UniqueId<String>> MemoryStore<T> {
-    pub fn new() -> Self {
-        MemoryStore { cache: Vec::new() }
-    }
-}
-
-impl<T: Clone + UniqueId<String>> Store<String, T> for MemoryStore<T> {
-    fn insert(&mut self, item: T) -> Result<T, Error> {
-        self.cache.push(item.clone());
-        Ok(item)
-    }
-
-    fn update(&mut self, id: String, item: T) -> Result<T, Error> {
-        if let Some(result) = self.cache.iter_mut().find(|p| p.get_id().eq(&Some(&id))) {
-            *result = item;
-            Ok(result.clone())
-        } else {
-            Err(Error::new(std::io::ErrorKind::NotFound, "Not found"))
-        }
-    }
-
-    fn get_by_id(&self, id: String) -> Result<T, Error> {
-        if let Some(result) = self.cache.iter().find(|p| p.get_id().eq(&Some(&id))) {
-            Ok(result.clone())
-        } else {
-            Err(Error::new(std::io::ErrorKind::NotFound, "Not found"))
-        }
-    }
+/// Whether the write failed because it would have broken a unique index.
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
 
-    fn list(&self) -> Result<Vec<T>, Error> {
-        Ok(self.cache.clone())
+    const DUPLICATE_KEY: i32 = 11000;
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        _ => false,
     }
 }
@@ -0,0 +1,122 @@
+use std::io::Error;
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use super::db_error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    LoginSucceeded,
+    LoginFailed,
+    OtpSucceeded,
+    OtpFailed,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    RecoveryCodesRegenerated,
+    PasswordChanged,
+    SessionRevoked,
+    TokenCreated,
+    Suspended,
+    Unsuspended,
+    PasswordResetForced,
+    TwoFactorReset,
+    RoleChanged,
+    Warned,
+    ContentRemoved,
+    DataExportRequested,
+    DataImportRequested,
+    DeletionScheduled,
+    DeletionCancelled,
+}
+
+impl AuditKind {
+    pub const ALL: [AuditKind; 21] = [
+        AuditKind::LoginSucceeded,
+        AuditKind::LoginFailed,
+        AuditKind::OtpSucceeded,
+        AuditKind::OtpFailed,
+        AuditKind::TwoFactorEnabled,
+        AuditKind::TwoFactorDisabled,
+        AuditKind::RecoveryCodesRegenerated,
+        AuditKind::PasswordChanged,
+        AuditKind::SessionRevoked,
+        AuditKind::TokenCreated,
+        AuditKind::Suspended,
+        AuditKind::Unsuspended,
+        AuditKind::PasswordResetForced,
+        AuditKind::TwoFactorReset,
+        AuditKind::RoleChanged,
+        AuditKind::Warned,
+        AuditKind::ContentRemoved,
+        AuditKind::DataExportRequested,
+        AuditKind::DataImportRequested,
+        AuditKind::DeletionScheduled,
+        AuditKind::DeletionCancelled,
+    ];
+}
+
+/// Something that happened to an account. Entries are only ever appended.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub _id: String,
+    pub kind: AuditKind,
+    /// The account the event is about.
+    pub uid: String,
+    /// Who caused the event, when it was someone other than `uid`.
+    pub actor: Option<String>,
+    pub detail: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: usize,
+}
+
+/// Narrows down [`AuditRepository::list`], fields left `None` match anything.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    pub uid: Option<String>,
+    pub kind: Option<AuditKind>,
+}
+
+#[async_trait]
+pub trait AuditRepository {
+    async fn insert(&self, event: AuditEvent) -> Result<AuditEvent, Error>;
+    /// Most recent events first.
+    async fn list(&self, filter: &AuditFilter, limit: i64) -> Result<Vec<AuditEvent>, Error>;
+}
+
+#[async_trait]
+impl AuditRepository for Collection<AuditEvent> {
+    async fn insert(&self, event: AuditEvent) -> Result<AuditEvent, Error> {
+        self.insert_one(&event, None).await.map_err(db_error)?;
+        Ok(event)
+    }
+
+    async fn list(&self, filter: &AuditFilter, limit: i64) -> Result<Vec<AuditEvent>, Error> {
+        let mut query = doc! {};
+        if let Some(uid) = &filter.uid {
+            query.insert("uid", uid);
+        }
+        if let Some(kind) = filter.kind {
+            query.insert("kind", to_bson(&kind).map_err(Error::other)?);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .build();
+        let mut cursor = self.find(query, options).await.map_err(db_error)?;
+        let mut events = Vec::new();
+        while let Some(event) = cursor.next().await {
+            events.push(event.map_err(db_error)?);
+        }
+        Ok(events)
+    }
+}
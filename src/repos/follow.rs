@@ -0,0 +1,107 @@
+use std::io::Error;
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, to_document},
+    options::UpdateOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use super::db_error;
+use crate::timestamp;
+
+/// `follower` following `followee`. The id is made of both, so following
+/// someone twice keeps a single record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Follow {
+    pub _id: String,
+    pub follower: String,
+    pub followee: String,
+    pub created_at: usize,
+}
+
+impl Follow {
+    pub fn new(follower: &str, followee: &str) -> Self {
+        Follow {
+            _id: format!("{}:{}", follower, followee),
+            follower: follower.to_string(),
+            followee: followee.to_string(),
+            created_at: timestamp(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait FollowRepository {
+    /// Records the follow, returning whether it is new.
+    async fn follow(&self, follow: &Follow) -> Result<bool, Error>;
+    /// Returns whether there was a follow to remove.
+    async fn unfollow(&self, follower: &str, followee: &str) -> Result<bool, Error>;
+    /// Ids of the accounts `uid` follows.
+    async fn following(&self, uid: &str) -> Result<Vec<String>, Error>;
+    /// Ids of the accounts following `uid`.
+    async fn followers(&self, uid: &str) -> Result<Vec<String>, Error>;
+    /// Removes every follow from or of `uid`.
+    async fn remove_all(&self, uid: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl FollowRepository for Collection<Follow> {
+    async fn follow(&self, follow: &Follow) -> Result<bool, Error> {
+        let document = to_document(follow).map_err(Error::other)?;
+        let options = UpdateOptions::builder().upsert(true).build();
+        let result = self
+            .update_one(
+                doc! { "_id": &follow._id },
+                doc! { "$setOnInsert": document },
+                options,
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(result.upserted_id.is_some())
+    }
+
+    async fn unfollow(&self, follower: &str, followee: &str) -> Result<bool, Error> {
+        let result = self
+            .delete_one(doc! { "follower": follower, "followee": followee }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(result.deleted_count > 0)
+    }
+
+    async fn following(&self, uid: &str) -> Result<Vec<String>, Error> {
+        let mut cursor = self
+            .find(doc! { "follower": uid }, None)
+            .await
+            .map_err(db_error)?;
+        let mut uids = Vec::new();
+        while let Some(follow) = cursor.next().await {
+            uids.push(follow.map_err(db_error)?.followee);
+        }
+        Ok(uids)
+    }
+
+    async fn followers(&self, uid: &str) -> Result<Vec<String>, Error> {
+        let mut cursor = self
+            .find(doc! { "followee": uid }, None)
+            .await
+            .map_err(db_error)?;
+        let mut uids = Vec::new();
+        while let Some(follow) = cursor.next().await {
+            uids.push(follow.map_err(db_error)?.follower);
+        }
+        Ok(uids)
+    }
+
+    async fn remove_all(&self, uid: &str) -> Result<(), Error> {
+        self.delete_many(
+            doc! { "$or": [{ "follower": uid }, { "followee": uid }] },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{bson::doc, Collection};
+use serde::{Deserialize, Serialize};
+
+use super::db_error;
+
+/// An account at an OpenID Connect provider linked to a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub _id: String,
+    pub uid: String,
+    pub provider: String,
+    /// The provider's `sub` claim, unique per provider.
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: usize,
+}
+
+#[async_trait]
+pub trait IdentityRepository {
+    async fn insert(&self, identity: Identity) -> Result<Identity, Error>;
+    async fn get(&self, provider: &str, subject: &str) -> Result<Identity, Error>;
+    async fn list(&self, uid: &str) -> Result<Vec<Identity>, Error>;
+    async fn remove(&self, uid: &str, id: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl IdentityRepository for Collection<Identity> {
+    async fn insert(&self, identity: Identity) -> Result<Identity, Error> {
+        if self
+            .get(&identity.provider, &identity.subject)
+            .await
+            .is_ok()
+        {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Already linked"));
+        }
+        self.insert_one(&identity, None).await.map_err(db_error)?;
+        Ok(identity)
+    }
+
+    async fn get(&self, provider: &str, subject: &str) -> Result<Identity, Error> {
+        self.find_one(doc! { "provider": provider, "subject": subject }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn list(&self, uid: &str) -> Result<Vec<Identity>, Error> {
+        let mut cursor = self
+            .find(doc! { "uid": uid }, None)
+            .await
+            .map_err(db_error)?;
+        let mut identities = Vec::new();
+        while let Some(identity) = cursor.next().await {
+            identities.push(identity.map_err(db_error)?);
+        }
+        Ok(identities)
+    }
+
+    async fn remove(&self, uid: &str, id: &str) -> Result<(), Error> {
+        let result = self
+            .delete_one(doc! { "_id": id, "uid": uid }, None)
+            .await
+            .map_err(db_error)?;
+        if result.deleted_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::{FindOneAndUpdateOptions, FindOneOptions, ReturnDocument},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::db_error;
+use crate::timestamp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Export,
+    Import,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Work run by the background worker on behalf of a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub _id: String,
+    pub kind: JobKind,
+    pub uid: String,
+    pub status: JobStatus,
+    pub error: Option<String>,
+    pub created_at: usize,
+    /// Bumped while the job runs, so jobs left behind by a crashed worker
+    /// can be told apart from slow ones.
+    pub updated_at: usize,
+    pub finished_at: Option<usize>,
+    /// Items the job has finished so far, a requeued job resumes after
+    /// them.
+    #[serde(default)]
+    pub checkpoint: usize,
+    /// Items the job has to go through, once known.
+    #[serde(default)]
+    pub total: Option<usize>,
+}
+
+impl Job {
+    /// Running jobs not updated for this long are queued again.
+    pub const STALE_AFTER: usize = 60 * 10;
+
+    pub fn new(kind: JobKind, uid: &str) -> Self {
+        let now = timestamp();
+        Job {
+            _id: Uuid::new_v4().to_string(),
+            kind,
+            uid: uid.to_string(),
+            status: JobStatus::Queued,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            finished_at: None,
+            checkpoint: 0,
+            total: None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+#[async_trait]
+pub trait JobRepository {
+    async fn insert(&self, job: Job) -> Result<Job, Error>;
+    /// The most recent job of `kind` started by `uid`.
+    async fn latest(&self, uid: &str, kind: JobKind) -> Result<Job, Error>;
+    /// Marks the oldest queued job as running and returns it.
+    async fn claim_next(&self) -> Result<Option<Job>, Error>;
+    async fn touch(&self, id: &str) -> Result<(), Error>;
+    /// Records that `done` of `total` items are finished.
+    async fn checkpoint(&self, id: &str, done: usize, total: usize) -> Result<(), Error>;
+    async fn finish(&self, id: &str, error: Option<String>) -> Result<(), Error>;
+    /// Queues running jobs that stopped being updated again.
+    async fn requeue_stale(&self) -> Result<(), Error>;
+    async fn list(&self, uid: &str) -> Result<Vec<Job>, Error>;
+    async fn remove_all(&self, uid: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl JobRepository for Collection<Job> {
+    async fn insert(&self, job: Job) -> Result<Job, Error> {
+        self.insert_one(&job, None).await.map_err(db_error)?;
+        Ok(job)
+    }
+
+    async fn latest(&self, uid: &str, kind: JobKind) -> Result<Job, Error> {
+        let kind = to_bson(&kind).map_err(Error::other)?;
+        let options = FindOneOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .build();
+        self.find_one(doc! { "uid": uid, "kind": kind }, options)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn claim_next(&self) -> Result<Option<Job>, Error> {
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .return_document(ReturnDocument::After)
+            .build();
+        self.find_one_and_update(
+            doc! { "status": "queued" },
+            doc! { "$set": { "status": "running", "updated_at": timestamp() as i64 } },
+            options,
+        )
+        .await
+        .map_err(db_error)
+    }
+
+    async fn touch(&self, id: &str) -> Result<(), Error> {
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "updated_at": timestamp() as i64 } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn checkpoint(&self, id: &str, done: usize, total: usize) -> Result<(), Error> {
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": {
+                "checkpoint": done as i64,
+                "total": total as i64,
+                "updated_at": timestamp() as i64,
+            } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn finish(&self, id: &str, error: Option<String>) -> Result<(), Error> {
+        let status = if error.is_some() { "failed" } else { "done" };
+        let now = timestamp() as i64;
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": {
+                "status": status,
+                "error": error,
+                "updated_at": now,
+                "finished_at": now,
+            } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn requeue_stale(&self) -> Result<(), Error> {
+        let cutoff = timestamp().saturating_sub(Job::STALE_AFTER) as i64;
+        self.update_many(
+            doc! { "status": "running", "updated_at": { "$lt": cutoff } },
+            doc! { "$set": { "status": "queued" } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn list(&self, uid: &str) -> Result<Vec<Job>, Error> {
+        let mut cursor = self
+            .find(doc! { "uid": uid }, None)
+            .await
+            .map_err(db_error)?;
+        let mut jobs = Vec::new();
+        while let Some(job) = cursor.next().await {
+            jobs.push(job.map_err(db_error)?);
+        }
+        Ok(jobs)
+    }
+
+    async fn remove_all(&self, uid: &str) -> Result<(), Error> {
+        self.delete_many(doc! { "uid": uid }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
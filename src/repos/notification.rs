@@ -0,0 +1,98 @@
+use std::io::Error;
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{bson::doc, options::FindOptions, Collection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::db_error;
+use crate::timestamp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    Followed,
+    Replied,
+}
+
+/// Something `actor` did that `uid` is told about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub _id: String,
+    pub uid: String,
+    pub kind: NotificationKind,
+    pub actor: String,
+    /// The post the notification is about, for replies.
+    pub post: Option<String>,
+    pub read: bool,
+    pub created_at: usize,
+}
+
+impl Notification {
+    pub fn new(uid: &str, kind: NotificationKind, actor: &str, post: Option<String>) -> Self {
+        Notification {
+            _id: Uuid::new_v4().to_string(),
+            uid: uid.to_string(),
+            kind,
+            actor: actor.to_string(),
+            post,
+            read: false,
+            created_at: timestamp(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait NotificationRepository {
+    async fn insert(&self, notification: Notification) -> Result<Notification, Error>;
+    /// Newest first.
+    async fn list(&self, uid: &str, limit: i64) -> Result<Vec<Notification>, Error>;
+    async fn mark_read(&self, uid: &str) -> Result<(), Error>;
+    /// Removes the notifications of `uid` and the ones caused by them.
+    async fn remove_all(&self, uid: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl NotificationRepository for Collection<Notification> {
+    async fn insert(&self, notification: Notification) -> Result<Notification, Error> {
+        self.insert_one(&notification, None)
+            .await
+            .map_err(db_error)?;
+        Ok(notification)
+    }
+
+    async fn list(&self, uid: &str, limit: i64) -> Result<Vec<Notification>, Error> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .build();
+        let mut cursor = self
+            .find(doc! { "uid": uid }, options)
+            .await
+            .map_err(db_error)?;
+        let mut notifications = Vec::new();
+        while let Some(notification) = cursor.next().await {
+            notifications.push(notification.map_err(db_error)?);
+        }
+        Ok(notifications)
+    }
+
+    async fn mark_read(&self, uid: &str) -> Result<(), Error> {
+        self.update_many(
+            doc! { "uid": uid, "read": false },
+            doc! { "$set": { "read": true } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn remove_all(&self, uid: &str) -> Result<(), Error> {
+        self.delete_many(doc! { "$or": [{ "uid": uid }, { "actor": uid }] }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{bson::doc, options::UpdateOptions, Collection};
+use serde::{Deserialize, Serialize};
+
+use super::db_error;
+use super::token::Scope;
+use crate::timestamp;
+
+/// A third party app registered to request access on behalf of users. The
+/// `_id` is the OAuth `client_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuthApp {
+    pub _id: String,
+    /// The user who registered the app.
+    pub owner: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    /// Hash of the client secret, public clients don't have one.
+    pub secret_hash: Option<String>,
+    pub created_at: usize,
+}
+
+/// Authorization code waiting to be exchanged for a token, keyed by the
+/// code hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationCode {
+    pub _id: String,
+    pub client_id: String,
+    pub uid: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<Scope>,
+    pub code_challenge: String,
+    pub expires_at: usize,
+}
+
+/// Access a user has given an app, listed under authorized apps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub _id: String,
+    pub uid: String,
+    pub client_id: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: usize,
+}
+
+#[async_trait]
+pub trait OAuthAppRepository {
+    async fn insert(&self, app: OAuthApp) -> Result<OAuthApp, Error>;
+    async fn get(&self, client_id: &str) -> Result<OAuthApp, Error>;
+    async fn list(&self, owner: &str) -> Result<Vec<OAuthApp>, Error>;
+    async fn remove(&self, owner: &str, client_id: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl OAuthAppRepository for Collection<OAuthApp> {
+    async fn insert(&self, app: OAuthApp) -> Result<OAuthApp, Error> {
+        self.insert_one(&app, None).await.map_err(db_error)?;
+        Ok(app)
+    }
+
+    async fn get(&self, client_id: &str) -> Result<OAuthApp, Error> {
+        self.find_one(doc! { "_id": client_id }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn list(&self, owner: &str) -> Result<Vec<OAuthApp>, Error> {
+        let mut cursor = self
+            .find(doc! { "owner": owner }, None)
+            .await
+            .map_err(db_error)?;
+        let mut apps = Vec::new();
+        while let Some(app) = cursor.next().await {
+            apps.push(app.map_err(db_error)?);
+        }
+        Ok(apps)
+    }
+
+    async fn remove(&self, owner: &str, client_id: &str) -> Result<(), Error> {
+        let result = self
+            .delete_one(doc! { "_id": client_id, "owner": owner }, None)
+            .await
+            .map_err(db_error)?;
+        if result.deleted_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+pub trait AuthorizationCodeRepository {
+    async fn insert(&self, code: AuthorizationCode) -> Result<AuthorizationCode, Error>;
+    /// Removes and returns the unexpired code with hash `hash`, so each code
+    /// can only be redeemed once.
+    async fn take(&self, hash: &str) -> Result<AuthorizationCode, Error>;
+}
+
+#[async_trait]
+impl AuthorizationCodeRepository for Collection<AuthorizationCode> {
+    async fn insert(&self, code: AuthorizationCode) -> Result<AuthorizationCode, Error> {
+        self.insert_one(&code, None).await.map_err(db_error)?;
+        Ok(code)
+    }
+
+    async fn take(&self, hash: &str) -> Result<AuthorizationCode, Error> {
+        self.find_one_and_delete(doc! { "_id": hash }, None)
+            .await
+            .map_err(db_error)?
+            .filter(|c| c.expires_at > timestamp())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+}
+
+#[async_trait]
+pub trait GrantRepository {
+    /// Records that `uid` authorized `client_id`, replacing the scopes of an
+    /// earlier grant.
+    async fn upsert(&self, grant: Grant) -> Result<(), Error>;
+    async fn list(&self, uid: &str) -> Result<Vec<Grant>, Error>;
+    async fn remove(&self, uid: &str, client_id: &str) -> Result<(), Error>;
+    async fn remove_client(&self, client_id: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl GrantRepository for Collection<Grant> {
+    async fn upsert(&self, grant: Grant) -> Result<(), Error> {
+        let scopes: Vec<&str> = grant.scopes.iter().map(Scope::as_str).collect();
+        self.update_one(
+            doc! { "uid": &grant.uid, "client_id": &grant.client_id },
+            doc! {
+                "$set": { "scopes": scopes },
+                "$setOnInsert": { "_id": &grant._id, "created_at": grant.created_at as i64 },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn list(&self, uid: &str) -> Result<Vec<Grant>, Error> {
+        let mut cursor = self
+            .find(doc! { "uid": uid }, None)
+            .await
+            .map_err(db_error)?;
+        let mut grants = Vec::new();
+        while let Some(grant) = cursor.next().await {
+            grants.push(grant.map_err(db_error)?);
+        }
+        Ok(grants)
+    }
+
+    async fn remove(&self, uid: &str, client_id: &str) -> Result<(), Error> {
+        let result = self
+            .delete_one(doc! { "uid": uid, "client_id": client_id }, None)
+            .await
+            .map_err(db_error)?;
+        if result.deleted_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn remove_client(&self, client_id: &str) -> Result<(), Error> {
+        self.delete_many(doc! { "client_id": client_id }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, to_document},
+    options::{FindOptions, UpdateOptions},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::db_error;
+use crate::timestamp;
+
+/// A post, written here or imported from a Twitter archive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Post {
+    pub _id: String,
+    pub uid: String,
+    pub body: String,
+    /// When the post was first published, kept from the original on import.
+    pub created_at: usize,
+    /// Id of the tweet the post was imported from.
+    pub imported_from: Option<String>,
+    /// Id of the post a reply answers, or of the tweet an imported reply
+    /// answered.
+    pub in_reply_to: Option<String>,
+    /// Urls of the media attached to the original.
+    pub media: Vec<String>,
+}
+
+impl Post {
+    pub fn new(uid: &str, body: &str, in_reply_to: Option<String>) -> Self {
+        Post {
+            _id: Uuid::new_v4().to_string(),
+            uid: uid.to_string(),
+            body: body.to_string(),
+            created_at: timestamp(),
+            imported_from: None,
+            in_reply_to,
+            media: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait PostRepository {
+    /// Inserts an imported post unless the tweet it came from was imported
+    /// for the same user already, so an import can be run again.
+    async fn import(&self, post: &Post) -> Result<(), Error>;
+    async fn insert(&self, post: Post) -> Result<Post, Error>;
+    async fn get(&self, id: &str) -> Result<Post, Error>;
+    async fn remove(&self, id: &str) -> Result<(), Error>;
+    /// Every post of `uid`, oldest first.
+    async fn list(&self, uid: &str) -> Result<Vec<Post>, Error>;
+    /// Posts of any of `uids` published before `before`, newest first.
+    async fn feed(
+        &self,
+        uids: &[String],
+        before: Option<usize>,
+        limit: i64,
+    ) -> Result<Vec<Post>, Error>;
+}
+
+#[async_trait]
+impl PostRepository for Collection<Post> {
+    async fn import(&self, post: &Post) -> Result<(), Error> {
+        let document = to_document(post).map_err(Error::other)?;
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.update_one(
+            doc! { "uid": &post.uid, "imported_from": &post.imported_from },
+            doc! { "$setOnInsert": document },
+            options,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn insert(&self, post: Post) -> Result<Post, Error> {
+        self.insert_one(&post, None).await.map_err(db_error)?;
+        Ok(post)
+    }
+
+    async fn get(&self, id: &str) -> Result<Post, Error> {
+        self.find_one(doc! { "_id": id }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), Error> {
+        let result = self
+            .delete_one(doc! { "_id": id }, None)
+            .await
+            .map_err(db_error)?;
+        if result.deleted_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn list(&self, uid: &str) -> Result<Vec<Post>, Error> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .build();
+        let mut cursor = self
+            .find(doc! { "uid": uid }, options)
+            .await
+            .map_err(db_error)?;
+        let mut posts = Vec::new();
+        while let Some(post) = cursor.next().await {
+            posts.push(post.map_err(db_error)?);
+        }
+        Ok(posts)
+    }
+
+    async fn feed(
+        &self,
+        uids: &[String],
+        before: Option<usize>,
+        limit: i64,
+    ) -> Result<Vec<Post>, Error> {
+        let mut filter = doc! { "uid": { "$in": uids } };
+        if let Some(before) = before {
+            filter.insert("created_at", doc! { "$lt": before as i64 });
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .build();
+        let mut cursor = self.find(filter, options).await.map_err(db_error)?;
+        let mut posts = Vec::new();
+        while let Some(post) = cursor.next().await {
+            posts.push(post.map_err(db_error)?);
+        }
+        Ok(posts)
+    }
+}
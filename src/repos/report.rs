@@ -0,0 +1,253 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument},
+    Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{db_error, is_duplicate_key};
+use crate::timestamp;
+
+/// What kind of thing a report is about. Direct messages will be added here
+/// once they are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetKind {
+    Account,
+    Post,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportTarget {
+    pub kind: TargetKind,
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    Spam,
+    Harassment,
+    HateSpeech,
+    Impersonation,
+    Illegal,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportStatus {
+    Open,
+    Claimed,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Dismissed,
+    Warned,
+    Suspended,
+    /// The reported post was taken down, its author is left alone.
+    RemovedContent,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Dismissed => "dismissed",
+            Outcome::Warned => "warned",
+            Outcome::Suspended => "suspended",
+            Outcome::RemovedContent => "removed_content",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reporter {
+    pub uid: String,
+    pub reason: Reason,
+    pub comment: Option<String>,
+    pub created_at: usize,
+}
+
+/// Every report filed against a target until a moderator resolves it. Later
+/// reports of the same target are added to the unresolved one instead of
+/// opening another, a unique index keeps it to one unresolved report per
+/// target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub _id: String,
+    pub target: ReportTarget,
+    pub reporters: Vec<Reporter>,
+    pub status: ReportStatus,
+    /// The moderator who claimed the report.
+    pub moderator: Option<String>,
+    pub outcome: Option<Outcome>,
+    pub note: Option<String>,
+    pub created_at: usize,
+    pub resolved_at: Option<usize>,
+}
+
+#[async_trait]
+pub trait ReportRepository {
+    /// Creates the index allowing a single unresolved report per target.
+    async fn ensure_indexes(&self) -> Result<(), Error>;
+    /// Adds `reporter` to the unresolved report of `target`, opening one if
+    /// there is none. Fails with `AlreadyExists` if they already reported it.
+    async fn file(&self, target: ReportTarget, reporter: Reporter) -> Result<Report, Error>;
+    async fn get(&self, id: &str) -> Result<Report, Error>;
+    /// Reports `uid` took part in filing.
+    async fn filed_by(&self, uid: &str) -> Result<Vec<Report>, Error>;
+    /// Oldest first, so the queue is worked through in order.
+    async fn list(&self, status: ReportStatus, limit: i64) -> Result<Vec<Report>, Error>;
+    /// Assigns an open report to `moderator`.
+    async fn claim(&self, id: &str, moderator: &str) -> Result<(), Error>;
+    /// Closes a report claimed by `moderator`.
+    async fn resolve(
+        &self,
+        id: &str,
+        moderator: &str,
+        outcome: Outcome,
+        note: Option<String>,
+    ) -> Result<Report, Error>;
+}
+
+#[async_trait]
+impl ReportRepository for Collection<Report> {
+    async fn ensure_indexes(&self) -> Result<(), Error> {
+        let options = IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! { "resolved_at": { "$type": "null" } })
+            .build();
+        let index = IndexModel::builder()
+            .keys(doc! { "target": 1 })
+            .options(options)
+            .build();
+        self.create_index(index, None).await.map_err(db_error)?;
+        Ok(())
+    }
+
+    /// A single upsert, matching the unresolved report of the target unless
+    /// the reporter is on it already. When nothing matches a report is
+    /// opened, which the unique index refuses if the target has an unresolved
+    /// one. That happens when the reporter already reported it, or when
+    /// someone else opened one in between, so the upsert is tried once more.
+    async fn file(&self, target: ReportTarget, reporter: Reporter) -> Result<Report, Error> {
+        let filter = doc! {
+            "target": to_bson(&target).map_err(Error::other)?,
+            "resolved_at": null,
+            "reporters.uid": { "$ne": &reporter.uid },
+        };
+        let update = doc! {
+            "$push": { "reporters": to_bson(&reporter).map_err(Error::other)? },
+            "$setOnInsert": {
+                "_id": Uuid::new_v4().to_string(),
+                "status": "open",
+                "moderator": null,
+                "outcome": null,
+                "note": null,
+                "created_at": reporter.created_at as i64,
+            },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        for _ in 0..2 {
+            match self
+                .find_one_and_update(filter.clone(), update.clone(), options.clone())
+                .await
+            {
+                Ok(Some(report)) => return Ok(report),
+                Ok(None) => break,
+                Err(e) if is_duplicate_key(&e) => continue,
+                Err(e) => return Err(db_error(e)),
+            }
+        }
+        Err(Error::new(ErrorKind::AlreadyExists, "Already reported"))
+    }
+
+    async fn get(&self, id: &str) -> Result<Report, Error> {
+        self.find_one(doc! { "_id": id }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn filed_by(&self, uid: &str) -> Result<Vec<Report>, Error> {
+        let mut cursor = self
+            .find(doc! { "reporters.uid": uid }, None)
+            .await
+            .map_err(db_error)?;
+        let mut reports = Vec::new();
+        while let Some(report) = cursor.next().await {
+            reports.push(report.map_err(db_error)?);
+        }
+        Ok(reports)
+    }
+
+    async fn list(&self, status: ReportStatus, limit: i64) -> Result<Vec<Report>, Error> {
+        let status = to_bson(&status).map_err(Error::other)?;
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .limit(limit)
+            .build();
+        let mut cursor = self
+            .find(doc! { "status": status }, options)
+            .await
+            .map_err(db_error)?;
+        let mut reports = Vec::new();
+        while let Some(report) = cursor.next().await {
+            reports.push(report.map_err(db_error)?);
+        }
+        Ok(reports)
+    }
+
+    async fn claim(&self, id: &str, moderator: &str) -> Result<(), Error> {
+        let result = self
+            .update_one(
+                doc! { "_id": id, "status": "open" },
+                doc! { "$set": { "status": "claimed", "moderator": moderator } },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        if result.matched_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn resolve(
+        &self,
+        id: &str,
+        moderator: &str,
+        outcome: Outcome,
+        note: Option<String>,
+    ) -> Result<Report, Error> {
+        let result = self
+            .update_one(
+                doc! { "_id": id, "status": "claimed", "moderator": moderator },
+                doc! { "$set": {
+                    "status": "resolved",
+                    "outcome": outcome.as_str(),
+                    "note": note,
+                    "resolved_at": timestamp() as i64,
+                } },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        if result.matched_count == 0 {
+            return Err(Error::new(ErrorKind::NotFound, "Not found"));
+        }
+        self.get(id).await
+    }
+}
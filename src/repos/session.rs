@@ -0,0 +1,95 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{bson::doc, Collection};
+use serde::{Deserialize, Serialize};
+
+use super::db_error;
+use crate::timestamp;
+
+/// Index entry for a logged in browser session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub _id: String,
+    /// Id of the session in the session store.
+    pub session_id: String,
+    pub uid: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: usize,
+    pub last_seen_at: usize,
+    pub expires_at: usize,
+}
+
+impl SessionRecord {
+    /// How often `last_seen_at` is written back, in seconds.
+    pub const TOUCH_INTERVAL: usize = 60;
+}
+
+#[async_trait]
+pub trait SessionRepository {
+    async fn insert(&self, record: SessionRecord) -> Result<SessionRecord, Error>;
+    async fn get(&self, uid: &str, id: &str) -> Result<SessionRecord, Error>;
+    async fn get_by_session(&self, session_id: &str) -> Result<SessionRecord, Error>;
+    async fn list(&self, uid: &str) -> Result<Vec<SessionRecord>, Error>;
+    async fn touch(&self, id: &str) -> Result<(), Error>;
+    async fn remove(&self, id: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl SessionRepository for Collection<SessionRecord> {
+    async fn insert(&self, record: SessionRecord) -> Result<SessionRecord, Error> {
+        self.insert_one(&record, None).await.map_err(db_error)?;
+        Ok(record)
+    }
+
+    async fn get(&self, uid: &str, id: &str) -> Result<SessionRecord, Error> {
+        self.find_one(doc! { "_id": id, "uid": uid }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn get_by_session(&self, session_id: &str) -> Result<SessionRecord, Error> {
+        self.find_one(doc! { "session_id": session_id }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn list(&self, uid: &str) -> Result<Vec<SessionRecord>, Error> {
+        let mut cursor = self
+            .find(
+                doc! { "uid": uid, "expires_at": { "$gt": timestamp() as i64 } },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        let mut records = Vec::new();
+        while let Some(record) = cursor.next().await {
+            records.push(record.map_err(db_error)?);
+        }
+        records.sort_by_key(|r| std::cmp::Reverse(r.last_seen_at));
+        Ok(records)
+    }
+
+    async fn touch(&self, id: &str) -> Result<(), Error> {
+        let now = timestamp() as i64;
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "last_seen_at": now } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), Error> {
+        self.delete_one(doc! { "_id": id }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
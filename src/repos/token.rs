@@ -0,0 +1,295 @@
+use std::io::{Error, ErrorKind};
+
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use mongodb::{bson::doc, Collection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{db_error, is_duplicate_key};
+use crate::timestamp;
+
+/// Server side record of an issued refresh token, keyed by the token hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub _id: String,
+    pub uid: String,
+    pub created_at: usize,
+    pub expires_at: usize,
+    pub revoked: bool,
+}
+
+impl RefreshToken {
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.expires_at > timestamp()
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[async_trait]
+pub trait RefreshTokenRepository {
+    async fn insert(&self, token: RefreshToken) -> Result<RefreshToken, Error>;
+    /// Revokes the token if it is still active and returns it, in one update
+    /// so a token can't be redeemed twice by concurrent requests.
+    async fn take(&self, hash: &str) -> Result<RefreshToken, Error>;
+    async fn revoke(&self, hash: &str) -> Result<(), Error>;
+    async fn revoke_all(&self, uid: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl RefreshTokenRepository for Collection<RefreshToken> {
+    async fn insert(&self, token: RefreshToken) -> Result<RefreshToken, Error> {
+        self.insert_one(&token, None).await.map_err(db_error)?;
+        Ok(token)
+    }
+
+    async fn take(&self, hash: &str) -> Result<RefreshToken, Error> {
+        self.find_one_and_update(
+            doc! { "_id": hash, "revoked": false },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(db_error)?
+        .filter(|t| t.expires_at > timestamp())
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn revoke(&self, hash: &str) -> Result<(), Error> {
+        self.update_one(
+            doc! { "_id": hash },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn revoke_all(&self, uid: &str) -> Result<(), Error> {
+        self.update_many(
+            doc! { "uid": uid },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "write:posts")]
+    WritePosts,
+    #[serde(rename = "dm")]
+    Dm,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Read, Scope::WritePosts, Scope::Dm];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::WritePosts => "write:posts",
+            Scope::Dm => "dm",
+        }
+    }
+
+    /// Parses a space separated OAuth `scope` parameter, returning `None`
+    /// if it names an unknown scope.
+    pub fn parse_list(scopes: &str) -> Option<Vec<Scope>> {
+        let mut parsed = Vec::new();
+        for name in scopes.split_whitespace() {
+            let scope = Scope::ALL.into_iter().find(|s| s.as_str() == name)?;
+            if !parsed.contains(&scope) {
+                parsed.push(scope);
+            }
+        }
+        Some(parsed)
+    }
+
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Scopes granted to the current request. Only set for access tokens,
+/// personal or issued to OAuth apps. Session and JWT authenticated requests
+/// are not scope limited.
+#[derive(Debug, Clone)]
+pub struct Scopes(pub Vec<Scope>);
+
+/// A personal access token, or a token issued to an OAuth app when
+/// `client_id` is set. Only the hash of the token is stored, the token
+/// itself is shown to the user once when it is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessToken {
+    pub _id: String,
+    pub uid: String,
+    pub name: String,
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: usize,
+    pub expires_at: Option<usize>,
+    pub last_used_at: Option<usize>,
+    pub revoked: bool,
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+impl AccessToken {
+    pub const PREFIX: &'static str = "pat_";
+    pub const OAUTH_PREFIX: &'static str = "oat_";
+    /// Longest lifetime a personal access token can be created with.
+    pub const MAX_LIFETIME_DAYS: usize = 365;
+
+    /// Whether `token` looks like a token stored in this collection.
+    pub fn is_prefixed(token: &str) -> bool {
+        token.starts_with(Self::PREFIX) || token.starts_with(Self::OAUTH_PREFIX)
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.expires_at.is_none_or(|exp| exp > timestamp())
+    }
+}
+
+#[async_trait]
+pub trait AccessTokenRepository {
+    async fn insert(&self, token: AccessToken) -> Result<AccessToken, Error>;
+    async fn list(&self, uid: &str) -> Result<Vec<AccessToken>, Error>;
+    async fn get_active(&self, hash: &str) -> Result<AccessToken, Error>;
+    async fn touch(&self, id: &str) -> Result<(), Error>;
+    async fn revoke(&self, uid: &str, id: &str) -> Result<(), Error>;
+    async fn revoke_client(&self, uid: &str, client_id: &str) -> Result<(), Error>;
+    async fn revoke_app(&self, client_id: &str) -> Result<(), Error>;
+}
+
+#[async_trait]
+impl AccessTokenRepository for Collection<AccessToken> {
+    async fn insert(&self, token: AccessToken) -> Result<AccessToken, Error> {
+        self.insert_one(&token, None).await.map_err(db_error)?;
+        Ok(token)
+    }
+
+    async fn list(&self, uid: &str) -> Result<Vec<AccessToken>, Error> {
+        let mut cursor = self
+            .find(
+                doc! { "uid": uid, "revoked": false, "client_id": null },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        let mut tokens = Vec::new();
+        while let Some(token) = cursor.next().await {
+            tokens.push(token.map_err(db_error)?);
+        }
+        Ok(tokens)
+    }
+
+    async fn get_active(&self, hash: &str) -> Result<AccessToken, Error> {
+        self.find_one(doc! { "hash": hash }, None)
+            .await
+            .map_err(db_error)?
+            .filter(|t| t.is_active())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn touch(&self, id: &str) -> Result<(), Error> {
+        let now = timestamp() as i64;
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "last_used_at": now } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn revoke(&self, uid: &str, id: &str) -> Result<(), Error> {
+        let result = self
+            .update_one(
+                doc! { "_id": id, "uid": uid },
+                doc! { "$set": { "revoked": true } },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        if result.matched_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn revoke_client(&self, uid: &str, client_id: &str) -> Result<(), Error> {
+        self.update_many(
+            doc! { "uid": uid, "client_id": client_id },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn revoke_app(&self, client_id: &str) -> Result<(), Error> {
+        self.update_many(
+            doc! { "client_id": client_id },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Marks a single use token as spent until it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsumedToken {
+    pub _id: String,
+    pub expires_at: usize,
+}
+
+#[async_trait]
+pub trait ConsumedTokenRepository {
+    async fn is_consumed(&self, jti: &str) -> Result<bool, Error>;
+    /// Records `jti` as used, returning false if it already was.
+    async fn consume(&self, jti: &str, expires_at: usize) -> Result<bool, Error>;
+}
+
+#[async_trait]
+impl ConsumedTokenRepository for Collection<ConsumedToken> {
+    async fn is_consumed(&self, jti: &str) -> Result<bool, Error> {
+        let existing = self
+            .find_one(doc! { "_id": jti }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(existing.is_some())
+    }
+
+    async fn consume(&self, jti: &str, expires_at: usize) -> Result<bool, Error> {
+        let token = ConsumedToken {
+            _id: jti.to_string(),
+            expires_at,
+        };
+        // the id is unique, so of two concurrent uses only one insert succeeds
+        match self.insert_one(&token, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+}
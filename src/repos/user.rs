@@ -1,41 +1,456 @@
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 
+use async_std::stream::StreamExt;
+use async_trait::async_trait;
+use libreauth::pass::HashBuilder;
+use mongodb::{
+    bson::{doc, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection,
+};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use super::{MemoryStore, Store, UniqueId};
+use super::{db_error, token::hash_token};
+use crate::timestamp;
+
+/// What a user is allowed to do beyond managing their own account. Roles are
+/// ordered, each one includes everything the previous one can do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub _id: String,
     pub username: String,
-    #[serde(skip)]
+    /// Argon2 hash of the password in PHC format, empty on accounts that
+    /// can't be logged into.
     pub password: String,
-    #[serde(skip)]
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub email_verified: bool,
     pub totp_enabled: bool,
-    #[serde(skip)]
     pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    #[serde(default)]
+    pub totp_last_step: Option<u64>,
+    #[serde(default)]
+    pub totp_failures: u32,
+    #[serde(default)]
+    pub totp_locked_until: Option<usize>,
+    #[serde(default)]
+    pub created_at: usize,
+    #[serde(default)]
+    pub last_login_at: Option<usize>,
+    /// Set while an admin has suspended the account, which keeps it from
+    /// logging in or using existing sessions and tokens.
+    #[serde(default)]
+    pub suspended_at: Option<usize>,
+    /// When a requested account deletion goes through, unless cancelled.
+    #[serde(default)]
+    pub deletion_due_at: Option<usize>,
+    /// Set once the account is purged. The record stays behind as a
+    /// tombstone so anything referring to it still resolves.
+    #[serde(default)]
+    pub deleted_at: Option<usize>,
 }
 
-impl UniqueId<String> for User {
-    fn get_id<'user>(&'user self) -> Option<&'user String> {
-        Some(&self._id)
+impl User {
+    pub const RECOVERY_CODE_COUNT: usize = 10;
+    /// Failed second factor attempts allowed before locking out.
+    pub const TOTP_MAX_FAILURES: u32 = 5;
+    /// Lockout after the first [`User::TOTP_MAX_FAILURES`] failures, doubled on
+    /// every further failure up to [`User::TOTP_MAX_LOCKOUT`].
+    pub const TOTP_BASE_LOCKOUT: usize = 30;
+    pub const TOTP_MAX_LOCKOUT: usize = 60 * 60;
+    /// Longest password that is hashed, the forms ask for at most this.
+    pub const PASSWORD_MAX_LEN: usize = 1024;
+
+    pub fn new(username: String, password: &str) -> Result<Self, Error> {
+        let mut user = User::without_password(username);
+        user.set_password(password)?;
+        Ok(user)
+    }
+
+    fn without_password(username: String) -> Self {
+        User {
+            _id: Uuid::new_v4().to_string(),
+            username,
+            password: String::new(),
+            role: Role::User,
+            email_verified: false,
+            totp_enabled: false,
+            totp_secret: None,
+            recovery_codes: Vec::new(),
+            totp_last_step: None,
+            totp_failures: 0,
+            totp_locked_until: None,
+            created_at: timestamp(),
+            last_login_at: None,
+            suspended_at: None,
+            deletion_due_at: None,
+            deleted_at: None,
+        }
+    }
+
+    /// Replaces the stored hash with one of `password`.
+    pub fn set_password(&mut self, password: &str) -> Result<(), Error> {
+        self.password = HashBuilder::new()
+            .min_len(1)
+            .max_len(Self::PASSWORD_MAX_LEN)
+            .finalize()
+            .and_then(|hasher| hasher.hash(password))
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        Ok(())
+    }
+
+    /// Checks `password` against the stored hash, in constant time.
+    pub fn verify_password(&self, password: &str) -> bool {
+        HashBuilder::from_phc(&self.password).is_ok_and(|hasher| hasher.is_valid(password))
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Strips everything personal from the account, leaving a record that
+    /// can't be logged into. The username is freed for a new registration.
+    pub fn tombstone(&mut self) {
+        let (id, created_at) = (self._id.clone(), self.created_at);
+        *self = User::without_password(format!("deleted:{}", id));
+        self._id = id;
+        self.created_at = created_at;
+        self.deleted_at = Some(timestamp());
+    }
+
+    /// Turns off two factor authentication and forgets the secret, recovery
+    /// codes and failed attempts that went with it.
+    pub fn reset_totp(&mut self) {
+        self.totp_enabled = false;
+        self.totp_secret = None;
+        self.recovery_codes.clear();
+        self.totp_last_step = None;
+        self.reset_totp_failures();
+    }
+
+    pub fn is_totp_locked(&self) -> bool {
+        self.totp_locked_until
+            .is_some_and(|until| until > timestamp())
+    }
+
+    /// Until when the failed attempts so far lock the user out, if there
+    /// were enough of them.
+    pub fn totp_lockout(&self, now: usize) -> Option<usize> {
+        let exponent = self.totp_failures.checked_sub(Self::TOTP_MAX_FAILURES)?;
+        let lockout = (Self::TOTP_BASE_LOCKOUT << exponent.min(16)).min(Self::TOTP_MAX_LOCKOUT);
+        Some(now + lockout)
+    }
+
+    pub fn reset_totp_failures(&mut self) {
+        self.totp_failures = 0;
+        self.totp_locked_until = None;
+    }
+
+    /// Replaces the user's recovery codes with a fresh set, returning the
+    /// plain codes. Only their hashes are kept on the user.
+    pub fn reset_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<String> = (0..Self::RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let id = Uuid::new_v4().simple().to_string();
+                format!("{}-{}", &id[..5], &id[5..10])
+            })
+            .collect();
+        self.recovery_codes = codes.iter().map(|c| hash_token(c)).collect();
+        codes
+    }
+}
+
+/// Public view of a [`User`] that is safe to hand back to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub id: String,
+    pub username: String,
+    pub role: Role,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+}
+
+impl From<&User> for Profile {
+    fn from(user: &User) -> Self {
+        Profile {
+            id: user._id.clone(),
+            username: user.username.clone(),
+            role: user.role,
+            email_verified: user.email_verified,
+            totp_enabled: user.totp_enabled,
+        }
     }
 }
 
-pub trait UserStore: Store<String, User> {
-    fn authenticate(&self, username: String, password: String) -> Result<User, Error>;
+#[async_trait]
+pub trait UserRepository {
+    async fn insert(&self, user: User) -> Result<User, Error>;
+    async fn update(&self, user: User) -> Result<User, Error>;
+    async fn get_by_id(&self, id: &str) -> Result<User, Error>;
+    async fn get_by_username(&self, username: &str) -> Result<User, Error>;
+    async fn authenticate(&self, username: &str, password: &str) -> Result<User, Error>;
+    async fn record_login(&self, id: &str) -> Result<(), Error>;
+    /// Records `step` as the last accepted totp step unless it or a later
+    /// one was used already, or the user is locked out. Returns whether it
+    /// was recorded.
+    async fn claim_totp_step(&self, id: &str, step: u64) -> Result<bool, Error>;
+    /// Removes the recovery code `code` unless the user is locked out,
+    /// returning whether it was there.
+    async fn use_recovery_code(&self, id: &str, code: &str) -> Result<bool, Error>;
+    /// Counts a failed second factor attempt, locking the user out once
+    /// there were too many.
+    async fn record_totp_failure(&self, id: &str) -> Result<(), Error>;
+    async fn reset_totp_failures(&self, id: &str) -> Result<(), Error>;
+    /// Users whose username contains `query`, ignoring case.
+    async fn search(&self, query: &str, limit: i64) -> Result<Vec<User>, Error>;
+    /// Accounts whose deletion grace period ended by `now`.
+    async fn due_for_deletion(&self, now: usize) -> Result<Vec<User>, Error>;
 }
 
-impl UserStore for MemoryStore<User> {
-    fn authenticate(&self, username: String, password: String) -> Result<User, Error> {
-        if let Some(result) = self
-            .cache
-            .iter()
-            .find(|p| p.username == username && p.password == password)
-        {
-            Ok(result.clone())
+#[async_trait]
+impl UserRepository for Collection<User> {
+    async fn insert(&self, user: User) -> Result<User, Error> {
+        if self.get_by_username(&user.username).await.is_ok() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Username taken"));
+        }
+        self.insert_one(&user, None).await.map_err(db_error)?;
+        Ok(user)
+    }
+
+    async fn update(&self, user: User) -> Result<User, Error> {
+        let result = self
+            .replace_one(doc! { "_id": &user._id }, &user, None)
+            .await
+            .map_err(db_error)?;
+        if result.matched_count == 0 {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
         } else {
-            Err(Error::new(std::io::ErrorKind::NotFound, "Not found"))
+            Ok(user)
         }
     }
+
+    async fn get_by_id(&self, id: &str) -> Result<User, Error> {
+        self.find_one(doc! { "_id": id }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn get_by_username(&self, username: &str) -> Result<User, Error> {
+        self.find_one(doc! { "username": username }, None)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<User, Error> {
+        let user = self.get_by_username(username).await?;
+        if !user.verify_password(password) || user.is_deleted() {
+            Err(Error::new(ErrorKind::NotFound, "Not found"))
+        } else if user.is_suspended() {
+            Err(Error::new(ErrorKind::PermissionDenied, "Account suspended"))
+        } else {
+            Ok(user)
+        }
+    }
+
+    async fn record_login(&self, id: &str) -> Result<(), Error> {
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "last_login_at": timestamp() as i64 } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn claim_totp_step(&self, id: &str, step: u64) -> Result<bool, Error> {
+        let mut filter = unlocked(id);
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "totp_last_step": null },
+                doc! { "totp_last_step": { "$lt": step as i64 } },
+            ],
+        );
+        let result = self
+            .update_one(
+                filter,
+                doc! { "$set": { "totp_last_step": step as i64 } },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn use_recovery_code(&self, id: &str, code: &str) -> Result<bool, Error> {
+        let hash = hash_token(code.trim());
+        let mut filter = unlocked(id);
+        filter.insert("recovery_codes", &hash);
+        let result = self
+            .update_one(filter, doc! { "$pull": { "recovery_codes": &hash } }, None)
+            .await
+            .map_err(db_error)?;
+        Ok(result.modified_count == 1)
+    }
+
+    async fn record_totp_failure(&self, id: &str) -> Result<(), Error> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let user = self
+            .find_one_and_update(
+                doc! { "_id": id },
+                doc! { "$inc": { "totp_failures": 1 } },
+                options,
+            )
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Not found"))?;
+        if let Some(until) = user.totp_lockout(timestamp()) {
+            // concurrent failures each compute their own lockout, the
+            // longest one wins
+            self.update_one(
+                doc! { "_id": id },
+                doc! { "$max": { "totp_locked_until": until as i64 } },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    async fn reset_totp_failures(&self, id: &str) -> Result<(), Error> {
+        self.update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "totp_failures": 0, "totp_locked_until": null } },
+            None,
+        )
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn search(&self, query: &str, limit: i64) -> Result<Vec<User>, Error> {
+        let options = FindOptions::builder()
+            .sort(doc! { "username": 1 })
+            .limit(limit)
+            .build();
+        let pattern = regex_escape(query.trim());
+        let mut cursor = self
+            .find(
+                doc! { "username": { "$regex": pattern, "$options": "i" } },
+                options,
+            )
+            .await
+            .map_err(db_error)?;
+        let mut users = Vec::new();
+        while let Some(user) = cursor.next().await {
+            users.push(user.map_err(db_error)?);
+        }
+        Ok(users)
+    }
+
+    async fn due_for_deletion(&self, now: usize) -> Result<Vec<User>, Error> {
+        let mut cursor = self
+            .find(
+                doc! { "deletion_due_at": { "$lte": now as i64 }, "deleted_at": null },
+                None,
+            )
+            .await
+            .map_err(db_error)?;
+        let mut users = Vec::new();
+        while let Some(user) = cursor.next().await {
+            users.push(user.map_err(db_error)?);
+        }
+        Ok(users)
+    }
+}
+
+/// Matches the user `id` unless a second factor lockout is in effect.
+fn unlocked(id: &str) -> Document {
+    doc! {
+        "_id": id,
+        "totp_locked_until": { "$not": { "$gt": timestamp() as i64 } },
+    }
+}
+
+fn regex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::User;
+
+    #[test]
+    fn stores_only_a_hash_of_the_password() {
+        let user = User::new(String::from("a@example.com"), "correct horse").unwrap();
+        assert!(!user.password.contains("correct horse"));
+        assert!(user.password.starts_with("$argon2"));
+        assert!(user.verify_password("correct horse"));
+        assert!(!user.verify_password("correct horsf"));
+        assert!(!user.verify_password(""));
+    }
+
+    #[test]
+    fn locks_out_with_a_doubling_delay() {
+        let mut user = User::new(String::from("a@example.com"), "correct horse").unwrap();
+        user.totp_failures = User::TOTP_MAX_FAILURES - 1;
+        assert_eq!(user.totp_lockout(100), None);
+        user.totp_failures = User::TOTP_MAX_FAILURES;
+        assert_eq!(user.totp_lockout(100), Some(100 + User::TOTP_BASE_LOCKOUT));
+        user.totp_failures = User::TOTP_MAX_FAILURES + 1;
+        assert_eq!(
+            user.totp_lockout(100),
+            Some(100 + 2 * User::TOTP_BASE_LOCKOUT)
+        );
+        user.totp_failures = u32::MAX;
+        assert_eq!(user.totp_lockout(100), Some(100 + User::TOTP_MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn tombstones_cannot_be_logged_into() {
+        let mut user = User::new(String::from("a@example.com"), "correct horse").unwrap();
+        user.tombstone();
+        assert!(!user.verify_password("correct horse"));
+        assert!(!user.verify_password(""));
+    }
 }
@@ -1,31 +1,90 @@
-use mongodb::Collection;
+use std::net::SocketAddr;
+
+use async_session::Session;
+use async_trait::async_trait;
 use serde::Serialize;
+use tide::http::{content::Accept, mime, Mime, Url};
 use tide::Request;
+use uuid::Uuid;
 
-use crate::{registry::State, repos::user::User, Claims};
+use crate::{
+    forms::{self, FormState},
+    registry::State,
+    repos::audit::{AuditEvent, AuditKind, AuditRepository},
+    repos::session::{SessionRecord, SessionRepository},
+    repos::user::{User, UserRepository},
+    sessions, timestamp, Claims, OriginalUrl, RequestId, SUDO_TTL,
+};
 
+#[async_trait]
 pub trait RequestExt {
-    fn is_authenticated(&mut self) -> bool;
+    async fn is_authenticated(&mut self) -> bool;
+    /// Whether the request was authenticated by a bearer token rather than
+    /// the session cookie.
+    fn is_bearer(&self) -> bool;
     fn user(&self) -> Option<&User>;
-    fn db<T: Serialize>(&self, name: &str) -> Collection<T>;
     fn requires_totp(&self) -> bool;
     fn clear_totp_redirect(&mut self);
     fn prevent_totp_redirect(&mut self) -> bool;
     fn claims(&self) -> Option<Claims>;
+    fn wants_json(&self) -> bool;
+    fn is_api(&self) -> bool;
+    fn original_path(&self) -> String;
+    fn set_return_to(&mut self, target: &str);
+    fn take_return_to(&mut self) -> String;
+    /// Keeps a rejected form for the next page that renders a template.
+    fn set_form_state(&mut self, form: FormState) -> Result<(), serde_json::Error>;
+    fn take_form_state(&mut self) -> FormState;
+    fn is_sudo(&self) -> bool;
+    fn sudo(&mut self) -> Result<(), serde_json::Error>;
+    fn client_ip(&self) -> Option<String>;
     fn login<Claims: Serialize>(&mut self, claims: Claims) -> Result<(), serde_json::Error>;
+    async fn start_session(&mut self, claims: Claims) -> tide::Result<()>;
+    async fn end_session(&mut self) -> tide::Result<()>;
+    async fn audit(&self, kind: AuditKind, uid: &str, detail: Option<String>) -> tide::Result<()>;
     fn logout(&mut self);
 }
 
+#[async_trait]
 impl RequestExt for Request<State> {
-    fn is_authenticated(&mut self) -> bool {
+    async fn is_authenticated(&mut self) -> bool {
+        if self.is_bearer() {
+            // already authenticated by a bearer token
+            return self
+                .user()
+                .map_or(false, |user| !user.is_suspended() && !user.is_deleted());
+        }
+
         if let Some(claims) = self.session().get::<Claims>("tide.uid") {
-            println!("claims, {:?}", claims);
-            if let Ok(user) = USERS.with_borrow(|db| db.get_by_id(claims.uid)) {
-                println!("user found, {:?}", user);
+            if claims.is_expired() {
+                tide::log::debug!("session of {} expired", claims.uid);
+                self.logout();
+                return false;
+            }
+            // sessions missing from the index were signed out remotely
+            let session_id = self.session().id().to_string();
+            match self.state().sessions().get_by_session(&session_id).await {
+                Ok(record) if record.uid == claims.uid => {
+                    if timestamp() >= record.last_seen_at + SessionRecord::TOUCH_INTERVAL {
+                        let _ = self.state().sessions().touch(&record._id).await;
+                    }
+                }
+                _ => {
+                    tide::log::debug!("session of {} was revoked", claims.uid);
+                    self.logout();
+                    return false;
+                }
+            }
+            if let Ok(user) = self.state().users().get_by_id(&claims.uid).await {
+                if user.is_suspended() || user.is_deleted() {
+                    tide::log::debug!("rejected session of inactive user {}", user._id);
+                    self.logout();
+                    return false;
+                }
                 self.set_ext(user);
                 true
             } else {
-                println!("user not found");
+                tide::log::debug!("session user {} no longer exists", claims.uid);
                 self.logout();
                 false
             }
@@ -34,17 +93,17 @@ impl RequestExt for Request<State> {
         }
     }
 
-    fn user(&self) -> Option<&User> {
-        self.ext::<User>()
+    fn is_bearer(&self) -> bool {
+        self.ext::<Claims>().is_some()
     }
 
-    fn db<T: Serialize>(&self, name: &str) -> Collection<T> {
-        self.state().db::<T>(name)
+    fn user(&self) -> Option<&User> {
+        self.ext::<User>()
     }
 
     fn requires_totp(&self) -> bool {
         self.claims()
-            .map_or(false, |c| c.totp_enabled && c.totp.is_none())
+            .is_some_and(|c| c.totp_enabled && c.totp.is_none())
     }
 
     fn clear_totp_redirect(&mut self) {
@@ -56,16 +115,87 @@ impl RequestExt for Request<State> {
             return false;
         }
 
-        if let Some(_) = self.session().get::<i32>("tide.totp-redirect") {
-            return true;
+        if self.session().get::<i32>("tide.totp-redirect").is_some() {
+            true
         } else {
             self.session_mut().insert("tide.totp-redirect", 1).unwrap();
-            return false;
+            false
         }
     }
 
     fn claims(&self) -> Option<Claims> {
-        self.session().get::<Claims>("tide.uid")
+        self.ext::<Claims>()
+            .cloned()
+            .or_else(|| self.session().get::<Claims>("tide.uid"))
+    }
+
+    fn wants_json(&self) -> bool {
+        let accept = match Accept::from_headers(self) {
+            Ok(Some(accept)) => accept,
+            _ => return false,
+        };
+        // weighed by hand, `Accept::negotiate` ranks entries without a q
+        // value below weighted ones instead of treating them as q=1
+        let weight = |mime: &Mime| {
+            accept
+                .iter()
+                .filter(|proposal| proposal.essence() == mime.essence())
+                .map(|proposal| proposal.weight().unwrap_or(1.0))
+                .fold(0.0, f32::max)
+        };
+        weight(&mime::JSON) > weight(&mime::HTML)
+    }
+
+    fn is_api(&self) -> bool {
+        self.wants_json() || self.original_path().starts_with("/api/")
+    }
+
+    fn original_path(&self) -> String {
+        let url = self.ext::<OriginalUrl>().map_or(self.url(), |u| &u.0);
+        match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        }
+    }
+
+    /// Remembers where to send the user once they have logged in, ignoring
+    /// anything that is not a path on this site.
+    fn set_return_to(&mut self, target: &str) {
+        if let Some(path) = same_origin_path(target) {
+            let _ = self.session_mut().insert("tide.login-redirect", path);
+        }
+    }
+
+    fn take_return_to(&mut self) -> String {
+        let target = self
+            .session()
+            .get::<String>("tide.login-redirect")
+            .and_then(|target| same_origin_path(&target));
+        self.session_mut().remove("tide.login-redirect");
+        target.unwrap_or_else(|| String::from("/"))
+    }
+
+    fn set_form_state(&mut self, form: FormState) -> Result<(), serde_json::Error> {
+        self.session_mut().insert(forms::SESSION_KEY, form)
+    }
+
+    fn take_form_state(&mut self) -> FormState {
+        let form = self.session().get::<FormState>(forms::SESSION_KEY);
+        if form.is_some() {
+            self.session_mut().remove(forms::SESSION_KEY);
+        }
+        form.unwrap_or_default()
+    }
+
+    fn is_sudo(&self) -> bool {
+        self.session()
+            .get::<usize>("tide.sudo")
+            .is_some_and(|exp| exp > timestamp())
+    }
+
+    fn sudo(&mut self) -> Result<(), serde_json::Error> {
+        let exp = timestamp() + SUDO_TTL.as_secs() as usize;
+        self.session_mut().insert("tide.sudo", exp)
     }
 
     fn login<Claims: Serialize>(&mut self, claims: Claims) -> Result<(), serde_json::Error> {
@@ -73,7 +203,134 @@ impl RequestExt for Request<State> {
         self.session_mut().insert("tide.uid", claims)
     }
 
+    fn client_ip(&self) -> Option<String> {
+        let addr = if self.state().config.trust_proxy_headers {
+            self.remote()
+        } else {
+            self.peer_addr()
+        };
+        addr.map(without_port)
+    }
+
+    /// Logs in with `claims` and records the session in the session index.
+    /// The session moves to a fresh id once the request is done, the record
+    /// is made under that id.
+    async fn start_session(&mut self, claims: Claims) -> tide::Result<()> {
+        let now = timestamp();
+        let cookie_value = Session::new().into_cookie_value().unwrap_or_default();
+        let record = SessionRecord {
+            _id: Uuid::new_v4().to_string(),
+            session_id: Session::id_from_cookie_value(&cookie_value)?,
+            uid: claims.uid.clone(),
+            user_agent: self.header("User-Agent").map(|h| h.as_str().to_string()),
+            ip: self.client_ip(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: claims.exp,
+        };
+        self.login(claims)?;
+        self.session_mut().insert(sessions::STARTED_AT, now)?;
+        self.session_mut()
+            .insert(sessions::RENEW_TO, cookie_value)?;
+        let record = self.state().sessions().insert(record).await?;
+        self.state().users().record_login(&record.uid).await?;
+        self.audit(AuditKind::LoginSucceeded, &record.uid, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Logs out and drops the session from the session index.
+    async fn end_session(&mut self) -> tide::Result<()> {
+        let session_id = self.session().id().to_string();
+        self.logout();
+        if let Ok(record) = self.state().sessions().get_by_session(&session_id).await {
+            self.state().sessions().remove(&record._id).await?;
+        }
+        Ok(())
+    }
+
+    /// Appends an event about `uid` to the audit log, attributed to the
+    /// logged in user when that is someone else.
+    async fn audit(&self, kind: AuditKind, uid: &str, detail: Option<String>) -> tide::Result<()> {
+        let actor = self.user().map(|u| u._id.clone()).filter(|id| id != uid);
+        let event = AuditEvent {
+            _id: Uuid::new_v4().to_string(),
+            kind,
+            uid: uid.to_string(),
+            actor,
+            detail,
+            ip: self.client_ip(),
+            user_agent: self.header("User-Agent").map(|h| h.as_str().to_string()),
+            request_id: self.ext::<RequestId>().map(|id| id.0.clone()),
+            created_at: timestamp(),
+        };
+        self.state().audit_events().insert(event).await?;
+        Ok(())
+    }
+
     fn logout(&mut self) {
         self.session_mut().destroy();
     }
 }
+
+/// The ip of `ip:port` or `[ipv6]:port`, forwarded headers may also carry
+/// the bare address.
+fn without_port(addr: &str) -> String {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+    }
+}
+
+/// Returns `target` as a path and query if it resolves to this site, so it is
+/// safe to redirect to. Rejects absolute and protocol relative urls, including
+/// the `/\host` form browsers treat as `//host`.
+pub fn same_origin_path(target: &str) -> Option<String> {
+    if !target.starts_with('/') {
+        return None;
+    }
+    let base = Url::parse("http://localhost/").unwrap();
+    let url = base.join(target).ok()?;
+    if url.origin() != base.origin() {
+        return None;
+    }
+    match url.query() {
+        Some(query) => Some(format!("{}?{}", url.path(), query)),
+        None => Some(url.path().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{same_origin_path, without_port};
+
+    #[test]
+    fn strips_the_port_from_client_addresses() {
+        assert_eq!(without_port("203.0.113.7:52100"), "203.0.113.7");
+        assert_eq!(without_port("203.0.113.7"), "203.0.113.7");
+        assert_eq!(without_port("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(without_port("2001:db8::1"), "2001:db8::1");
+        assert_eq!(without_port("[2001:db8::1]"), "2001:db8::1");
+    }
+
+    #[test]
+    fn keeps_redirects_on_this_site() {
+        assert_eq!(same_origin_path("/account").as_deref(), Some("/account"));
+        assert_eq!(same_origin_path("/a/../b?x=1").as_deref(), Some("/b?x=1"));
+        for target in [
+            "",
+            "//evil.example",
+            "/\\evil.example",
+            "/\\/evil.example",
+            "https://evil.example/",
+            "http://localhost/account",
+            "javascript:alert(1)",
+            "evil.example",
+        ] {
+            assert_eq!(same_origin_path(target), None, "{:?}", target);
+        }
+    }
+}
@@ -1,40 +1,311 @@
 use async_trait::async_trait;
-use tide::{Middleware, Next, Redirect, Request, Route};
+use tide::{http::Method, Middleware, Next, Redirect, Request, Route, StatusCode};
 
-use crate::prelude::RequestExt;
+use crate::json::ApiError;
+use crate::policy;
+use crate::prelude::*;
+use crate::ratelimit::RateLimitMiddleware;
+use crate::registry::State;
+use crate::repos::token::{Scope, Scopes};
+use crate::repos::user::Role;
 
 pub trait RouteExt {
     fn authenticated(&mut self) -> &mut Self;
+    fn api_authenticated(&mut self) -> &mut Self;
+    fn scope(&mut self, scope: Scope) -> &mut Self;
+    fn sudo(&mut self) -> &mut Self;
+    fn rate_limited(&mut self) -> &mut Self;
+    fn rate_limited_by_account(&mut self) -> &mut Self;
+    fn verified(&mut self) -> &mut Self;
+    fn require_role(&mut self, role: Role) -> &mut Self;
 }
 
-impl<'a, State: Clone + Send + Sync + 'static> RouteExt for Route<'a, State> {
+impl<'a> RouteExt for Route<'a, State> {
     fn authenticated(&mut self) -> &mut Self {
         self.with(AuthenticatedMiddleware {});
         self
     }
+
+    fn api_authenticated(&mut self) -> &mut Self {
+        self.with(ApiAuthenticatedMiddleware { scope: None });
+        self
+    }
+
+    fn scope(&mut self, scope: Scope) -> &mut Self {
+        self.with(ApiAuthenticatedMiddleware { scope: Some(scope) });
+        self
+    }
+
+    fn sudo(&mut self) -> &mut Self {
+        self.with(SudoMiddleware {});
+        self
+    }
+
+    fn rate_limited(&mut self) -> &mut Self {
+        self.with(RateLimitMiddleware { by_account: false });
+        self
+    }
+
+    fn rate_limited_by_account(&mut self) -> &mut Self {
+        self.with(RateLimitMiddleware { by_account: true });
+        self
+    }
+
+    fn verified(&mut self) -> &mut Self {
+        self.with(VerifiedMiddleware {});
+        self
+    }
+
+    fn require_role(&mut self, role: Role) -> &mut Self {
+        self.with(RoleMiddleware { role });
+        self
+    }
 }
 
+/// Sends browsers that are not logged in to the login page, remembering the
+/// page they asked for so they are brought back to it afterwards. Requests
+/// that accept JSON get a 401 instead. Only session logins get through.
+/// Bearer tokens of any kind are refused, including tokens held by OAuth
+/// apps, which must not be able to answer the consent screen themselves.
+/// Sessions still owing their second factor are sent to the login page to
+/// enter it, or get a 403.
 pub struct AuthenticatedMiddleware {}
 
 #[async_trait]
-impl<State> Middleware<State> for AuthenticatedMiddleware
-where
-    State: Clone + Send + Sync + 'static,
-{
+impl Middleware<State> for AuthenticatedMiddleware {
     async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
-        if !request.is_authenticated() {
-            // Redirect::new(/login?redirect=url)
-            // Redirect::new(/)
-            // 401 Unauthorized
-            // 403 Forbidden
+        if request.is_bearer() {
+            return ApiError::forbidden("bearer tokens can't be used here").into();
+        } else if !request.is_authenticated().await {
+            if request.wants_json() {
+                return unauthenticated();
+            }
+            if request.method() == Method::Get {
+                let target = request.original_path();
+                request.set_return_to(&target);
+            }
             return Ok(Redirect::new("/").into());
-            //return Ok(Response::new(StatusCode::Unauthorized));
-        } else if request.prevent_totp_redirect() {
-            // totp should be a one time redirect, require re-login
-            request.logout();
+        } else if request.requires_totp() {
+            // the password alone never gets past this point
+            if request.wants_json() {
+                return ApiError::forbidden("two factor authentication required").into();
+            }
+            if request.method() == Method::Get {
+                let target = request.original_path();
+                request.set_return_to(&target);
+            }
             return Ok(Redirect::new("/").into());
         }
 
-        return Ok(next.run(request).await);
+        Ok(next.run(request).await)
+    }
+}
+
+fn unauthenticated() -> tide::Result {
+    let mut res = tide::Result::from(ApiError::unauthorized("authentication required"))?;
+    res.insert_header("WWW-Authenticate", "Bearer");
+    Ok(res)
+}
+
+/// Guards API routes. Access tokens, which carry `Scopes`, only get through
+/// routes declaring a `scope` they were granted. Sessions and JWTs are not
+/// scope limited.
+pub struct ApiAuthenticatedMiddleware {
+    scope: Option<Scope>,
+}
+
+#[async_trait]
+impl Middleware<State> for ApiAuthenticatedMiddleware {
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !request.is_authenticated().await {
+            return unauthenticated();
+        } else if request.requires_totp() {
+            return ApiError::forbidden("two factor authentication required").into();
+        }
+        if let Some(Scopes(scopes)) = request.ext::<Scopes>() {
+            if !self.scope.is_some_and(|scope| scopes.contains(&scope)) {
+                return ApiError::forbidden("token is missing the required scope").into();
+            }
+        }
+
+        Ok(next.run(request).await)
+    }
+}
+
+/// Requires the session to have recently re-entered its password. Browsers
+/// are sent to confirm it and brought back afterwards, API clients get a 403.
+pub struct SudoMiddleware {}
+
+#[async_trait]
+impl Middleware<State> for SudoMiddleware {
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if request.is_sudo() {
+            return Ok(next.run(request).await);
+        } else if request.is_api() {
+            return ApiError::new(
+                StatusCode::Forbidden,
+                "sudo_required",
+                "confirm your password to continue",
+            )
+            .into();
+        }
+
+        let target = if request.method() == Method::Get {
+            request.original_path()
+        } else {
+            String::from("/account/settings")
+        };
+        request.session_mut().insert("tide.sudo-redirect", target)?;
+        Ok(Redirect::new("/account/sudo").into())
+    }
+}
+
+/// Keeps accounts that have not verified their email address out of the
+/// route, unless `REQUIRE_VERIFIED_EMAIL` is turned off.
+pub struct VerifiedMiddleware {}
+
+#[async_trait]
+impl Middleware<State> for VerifiedMiddleware {
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let verified = request.user().is_some_and(|u| u.email_verified);
+        if verified || !request.state().config.require_verified_email {
+            return Ok(next.run(request).await);
+        } else if request.is_api() {
+            return ApiError::new(
+                StatusCode::Forbidden,
+                "unverified",
+                "verify your email address to continue",
+            )
+            .into();
+        }
+
+        let mut res: tide::Response = Redirect::new("/account/settings").into();
+        res.flash_error("verify your email address to continue");
+        Ok(res)
+    }
+}
+
+/// Lets through users holding at least `role`. Goes after `authenticated()`
+/// or `api_authenticated()`, which load the user it checks.
+pub struct RoleMiddleware {
+    role: Role,
+}
+
+#[async_trait]
+impl Middleware<State> for RoleMiddleware {
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let allowed = request
+            .user()
+            .is_some_and(|user| policy::has_role(user, self.role));
+        if allowed {
+            return Ok(next.run(request).await);
+        } else if request.is_api() {
+            return ApiError::forbidden("you don't have permission to do that").into();
+        }
+
+        let mut res: tide::Response = Redirect::new("/").into();
+        res.flash_error("you don't have permission to view that page");
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tide::http::{Method, Url};
+    use tide::{Middleware, Next, Request, Server, StatusCode};
+
+    use super::RouteExt;
+    use crate::registry::State;
+    use crate::repos::token::{Scope, Scopes};
+    use crate::repos::user::User;
+    use crate::Claims;
+
+    /// Stands in for `BearerMiddleware`, authenticating every request as a
+    /// token holder. `None` is a JWT, `Some` an access token with its scopes.
+    struct TokenUser(Option<Vec<Scope>>);
+
+    #[async_trait]
+    impl Middleware<State> for TokenUser {
+        async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+            let user = User::new(String::from("a@example.com"), "correct horse").unwrap();
+            request.set_ext(Claims::new(&user, Duration::from_secs(60)));
+            request.set_ext(user);
+            if let Some(scopes) = &self.0 {
+                request.set_ext(Scopes(scopes.clone()));
+            }
+            Ok(next.run(request).await)
+        }
+    }
+
+    fn server(token: TokenUser) -> Server<State> {
+        let mut app = tide::with_state(State::for_tests());
+        app.with(token);
+        app.at("/account")
+            .authenticated()
+            .post(|_| async { Ok("ok") });
+        app.at("/api/account")
+            .api_authenticated()
+            .post(|_| async { Ok("ok") });
+        app.at("/api/profile")
+            .scope(Scope::Read)
+            .get(|_| async { Ok("ok") });
+        app
+    }
+
+    async fn status(app: &Server<State>, method: Method, path: &str) -> StatusCode {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let req = tide::http::Request::new(method, url);
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.status()
+    }
+
+    #[async_std::test]
+    async fn access_tokens_only_pass_routes_in_their_scope() {
+        let app = server(TokenUser(Some(vec![Scope::Read])));
+        assert_eq!(
+            status(&app, Method::Get, "/api/profile").await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(&app, Method::Post, "/api/account").await,
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            status(&app, Method::Post, "/account").await,
+            StatusCode::Forbidden
+        );
+
+        let app = server(TokenUser(Some(Vec::new())));
+        assert_eq!(
+            status(&app, Method::Get, "/api/profile").await,
+            StatusCode::Forbidden
+        );
+    }
+
+    #[async_std::test]
+    async fn jwts_are_not_scope_limited() {
+        let app = server(TokenUser(None));
+        assert_eq!(
+            status(&app, Method::Get, "/api/profile").await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            status(&app, Method::Post, "/api/account").await,
+            StatusCode::Ok
+        );
+    }
+
+    #[async_std::test]
+    async fn bearer_tokens_never_pass_the_browser_guard() {
+        for token in [None, Some(Scope::ALL.to_vec())] {
+            let app = server(TokenUser(token));
+            assert_eq!(
+                status(&app, Method::Post, "/account").await,
+                StatusCode::Forbidden
+            );
+        }
     }
 }
@@ -4,10 +4,24 @@ use validator::Validate;
 
 use crate::prelude::*;
 use crate::registry::State;
+use crate::repos::audit::AuditKind;
+use crate::repos::report::{Outcome, Reason, TargetKind};
+use crate::repos::user::{Role, User, UserRepository};
 use crate::templates::TemplateResponse;
 
 mod account;
+mod admin;
+mod api;
 mod auth;
+mod email;
+mod oauth;
+mod oidc;
+mod reports;
+
+#[derive(Deserialize)]
+struct RedirectQuery {
+    redirect: Option<String>,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct UserForm {
@@ -21,8 +35,9 @@ pub struct UserCreateForm {
     username: String,
     #[validate(length(
         min = 10,
+        max = 1024,
         code = "length",
-        message = "Password must be at least 10 characters"
+        message = "Password must be between 10 and 1024 characters"
     ))]
     #[validate(must_match(
         other = "confirm_password",
@@ -33,20 +48,188 @@ pub struct UserCreateForm {
     confirm_password: String,
 }
 
+#[derive(Deserialize)]
+pub struct RoleForm {
+    role: Role,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct ReportForm {
+    target_type: TargetKind,
+    target_id: String,
+    reason: Reason,
+    #[validate(length(
+        max = 1000,
+        code = "length",
+        message = "Comment must be at most 1000 characters"
+    ))]
+    comment: Option<String>,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct ResolveForm {
+    outcome: Outcome,
+    #[validate(length(
+        max = 1000,
+        code = "length",
+        message = "Note must be at most 1000 characters"
+    ))]
+    note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ForgotPasswordForm {
+    username: String,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct ResetPasswordForm {
+    #[validate(length(
+        min = 10,
+        max = 1024,
+        code = "length",
+        message = "Password must be between 10 and 1024 characters"
+    ))]
+    #[validate(must_match(
+        other = "confirm_password",
+        code = "must_match",
+        message = "Password and confirm password must match"
+    ))]
+    password: String,
+    confirm_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmailTokenForm {
+    token: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ValidateForm {
     code: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SudoForm {
+    password: String,
+    code: Option<String>,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct AccessTokenForm {
+    #[validate(length(min = 1, max = 64, code = "length", message = "Name is required"))]
+    name: String,
+    scope_read: Option<String>,
+    scope_write_posts: Option<String>,
+    scope_dm: Option<String>,
+    expires_in_days: Option<String>,
+}
+
+#[derive(Serialize, Validate, Deserialize)]
+pub struct OAuthAppForm {
+    #[validate(length(min = 1, max = 64, code = "length", message = "Name is required"))]
+    name: String,
+    /// Whitespace separated list of allowed redirect uris.
+    redirect_uris: String,
+    confidential: Option<String>,
+}
+
 pub fn configure(app: &mut Server<State>) {
     app.at("/").get(index);
     account::configure(app);
+    admin::configure(app);
     auth::configure(app);
+    email::configure(app);
+    oidc::configure(app);
+    oauth::configure(app);
+    api::configure(app);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondFactor {
+    Accepted,
+    Rejected,
+    LockedOut,
+}
+
+/// Checks `code` against the user's totp secret, optionally falling back to
+/// their recovery codes. Each totp time step is only accepted once, and
+/// repeated failures lock the user out with an exponential backoff. The
+/// outcome is persisted on the user so it can't be reset by the client, and
+/// recorded in the audit log. Every write is a single conditional update, so
+/// concurrent attempts can't reuse a step or recovery code.
+pub async fn verify_second_factor(
+    req: &Request<State>,
+    user: &User,
+    code: &str,
+    allow_recovery: bool,
+) -> tide::Result<SecondFactor> {
+    let users = req.state().users();
+    let user = users.get_by_id(&user._id).await?;
+    if user.is_totp_locked() {
+        return Ok(SecondFactor::LockedOut);
+    }
+
+    let key_ascii = user.totp_secret.clone().unwrap_or_default();
+    let accepted = match totp_step(&key_ascii, code) {
+        Some(step) if users.claim_totp_step(&user._id, step).await? => true,
+        _ => allow_recovery && users.use_recovery_code(&user._id, code).await?,
+    };
+
+    let (result, kind) = if accepted {
+        users.reset_totp_failures(&user._id).await?;
+        (SecondFactor::Accepted, AuditKind::OtpSucceeded)
+    } else {
+        users.record_totp_failure(&user._id).await?;
+        (SecondFactor::Rejected, AuditKind::OtpFailed)
+    };
+    req.audit(kind, &user._id, None).await?;
+    Ok(result)
+}
+
+/// Records a failed login against the account `username` names, if there
+/// is one.
+pub async fn audit_failed_login(req: &Request<State>, username: &str, error: &std::io::Error) {
+    let reason = if error.kind() == std::io::ErrorKind::PermissionDenied {
+        "account suspended"
+    } else {
+        "invalid password"
+    };
+    if let Ok(user) = req.state().users().get_by_username(username).await {
+        let detail = Some(reason.to_string());
+        if let Err(e) = req.audit(AuditKind::LoginFailed, &user._id, detail).await {
+            tide::log::error!("failed to record failed login: {}", e);
+        }
+    }
+}
+
+/// Returns the time step `code` is valid for, allowing one step of clock
+/// drift either side.
+pub fn totp_step(key_ascii: &str, code: &str) -> Option<u64> {
+    const PERIOD: u64 = 30;
+    let now = crate::timestamp() as u64 / PERIOD;
+    [now, now.saturating_sub(1), now + 1]
+        .into_iter()
+        .find(|step| {
+            libreauth::oath::TOTPBuilder::new()
+                .ascii_key(key_ascii)
+                .period(PERIOD as u32)
+                .timestamp((step * PERIOD) as i64)
+                .finalize()
+                .is_ok_and(|totp| totp.generate() == code.trim())
+        })
 }
 
 pub async fn index(mut req: Request<State>) -> tide::Result {
-    if !req.is_authenticated() {
-        TemplateResponse::new(req, "login.html").into()
+    if !req.is_authenticated().await {
+        let query = req.query::<RedirectQuery>().ok();
+        if let Some(target) = query.and_then(|q| q.redirect) {
+            req.set_return_to(&target);
+        }
+        let providers = req.state().oidc.providers().to_vec();
+        TemplateResponse::new(req, "login.html")
+            .with_data(serde_json::json!({ "providers": providers }))
+            .into()
     } else if req.prevent_totp_redirect() {
         req.logout();
         Ok(Redirect::new("/").into())
@@ -1,11 +1,22 @@
 use qrcode::render::svg;
 use serde_json::json;
-use tide::{Redirect, Request, Response, Server};
+use tide::{Body, Redirect, Request, Response, Server, StatusCode};
+use uuid::Uuid;
+use validator::Validate;
 
-use super::ValidateForm;
+use super::{
+    totp_step, verify_second_factor, AccessTokenForm, SecondFactor, SudoForm, ValidateForm,
+};
+use crate::error::AppError;
+use crate::forms::FormState;
+use crate::jobs::export::{self, EXPORT_TTL};
+use crate::policy;
 use crate::prelude::*;
-use crate::registry::USERS;
-use crate::repos::Store;
+use crate::repos::audit::{AuditEvent, AuditFilter, AuditKind, AuditRepository};
+use crate::repos::job::{Job, JobKind, JobRepository, JobStatus};
+use crate::repos::session::SessionRepository;
+use crate::repos::token::{hash_token, AccessToken, AccessTokenRepository, Scope};
+use crate::repos::user::{Role, UserRepository};
 use crate::templates::TemplateResponse;
 use crate::State;
 
@@ -14,42 +25,426 @@ pub fn configure(app: &mut Server<State>) {
     app.at("/account").authenticated().nest({
         let mut app = tide::with_state(state);
         app.at("/settings").get(settings);
-        app.at("/update-2fa").get(update_otp);
-        app.at("/validate-otp").post(validate_otp);
-        app.at("/logout").get(logout).post(logout);
+        app.at("/sudo")
+            .rate_limited_by_account()
+            .get(sudo)
+            .post(sudo_post);
+        app.at("/update-2fa")
+            .sudo()
+            .get(two_factor)
+            .post(update_otp);
+        app.at("/validate-otp").sudo().post(validate_otp);
+        app.at("/disable-2fa").sudo().post(disable_otp);
+        app.at("/recovery-codes")
+            .sudo()
+            .get(recovery_codes)
+            .post(regenerate_recovery_codes);
+        app.at("/resend-verification")
+            .post(super::email::resend_verification);
+        app.at("/report")
+            .get(super::reports::new_report)
+            .post(super::reports::file);
+        app.at("/tokens").verified().post(create_token);
+        app.at("/tokens/:id/revoke").post(revoke_token);
+        app.at("/identities").get(super::oidc::identities);
+        app.at("/identities/:provider/link")
+            .sudo()
+            .post(super::oidc::link);
+        app.at("/identities/:id/unlink")
+            .sudo()
+            .post(super::oidc::unlink);
+        app.at("/apps")
+            .get(super::oauth::apps)
+            .post(super::oauth::create_app);
+        app.at("/apps/:id/delete").post(super::oauth::delete_app);
+        app.at("/authorized-apps/:id/revoke")
+            .post(super::oauth::revoke_app);
+        app.at("/security").get(security);
+        app.at("/sessions").get(sessions);
+        app.at("/sessions/revoke-others")
+            .post(revoke_other_sessions);
+        app.at("/sessions/:id/revoke").post(revoke_session);
+        app.at("/export").sudo().post(request_export);
+        app.at("/export/download").get(download_export);
+        app.at("/delete").sudo().post(schedule_deletion);
+        app.at("/delete/cancel").post(cancel_deletion);
+        app.at("/logout").post(logout);
         app
     });
 }
 
 pub async fn logout(mut req: Request<State>) -> tide::Result {
-    req.logout();
+    req.end_session().await?;
     Ok(Redirect::new("/").into())
 }
 
 pub async fn settings(req: Request<State>) -> tide::Result {
-    TemplateResponse::new(req, "settings.html").into()
+    render_settings(req, None).await
 }
 
-pub async fn update_otp(mut req: Request<State>) -> tide::Result {
-    // TODO: turning on 2FA (totp) should require re-authenticating to enable/disable
-    // - enabling should display the setup procedure and show the qr code below
-    // - qr code should not be visible again after setup (no need to generate totp uri)
+async fn render_settings(req: Request<State>, new_token: Option<String>) -> tide::Result {
+    let user = req.user().unwrap();
+    let (uid, email_verified) = (user._id.clone(), user.email_verified);
+    let deletion_due_at = user.deletion_due_at;
+    let is_admin = policy::has_role(user, Role::Admin);
+    let is_moderator = policy::can_moderate(user);
+    let tokens = req.state().access_tokens().list(&uid).await?;
+    let export = req.state().jobs().latest(&uid, JobKind::Export).await.ok();
+    let import = req.state().jobs().latest(&uid, JobKind::Import).await.ok();
+    TemplateResponse::new(req, "settings.html")
+        .with_data(json!({
+            "tokens": tokens,
+            "new_token": new_token,
+            "email_verified": email_verified,
+            "is_admin": is_admin,
+            "is_moderator": is_moderator,
+            "export": export,
+            "import": import,
+            "deletion_due_at": deletion_due_at,
+        }))
+        .into()
+}
+
+pub async fn create_token(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_form::<AccessTokenForm>().await {
+        Ok(form) => form,
+        Err(e) => {
+            let mut res: Response = Redirect::new("/account/settings").into();
+            res.flash_error(e.to_string());
+            return Ok(res);
+        }
+    };
+    if let Err(e) = form.validate() {
+        req.set_form_state(FormState::new(&form).with_errors(&e))?;
+        return Ok(Redirect::new("/account/settings").into());
+    }
+
+    let scopes: Vec<Scope> = [
+        (Scope::Read, &form.scope_read),
+        (Scope::WritePosts, &form.scope_write_posts),
+        (Scope::Dm, &form.scope_dm),
+    ]
+    .into_iter()
+    .filter(|(_, checked)| checked.is_some())
+    .map(|(scope, _)| scope)
+    .collect();
+    let expires_at = match form.expires_in_days.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(days) => match days.parse::<usize>() {
+            Ok(days @ 1..=AccessToken::MAX_LIFETIME_DAYS) => {
+                Some(crate::timestamp() + days * 60 * 60 * 24)
+            }
+            _ => {
+                req.set_form_state(FormState::new(&form))?;
+                let mut res: Response = Redirect::new("/account/settings").into();
+                res.flash_error(format!(
+                    "expiry must be between 1 and {} days",
+                    AccessToken::MAX_LIFETIME_DAYS
+                ));
+                return Ok(res);
+            }
+        },
+    };
+
+    let token = format!(
+        "{}{}{}",
+        AccessToken::PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let access_token = req
+        .state()
+        .access_tokens()
+        .insert(AccessToken {
+            _id: Uuid::new_v4().to_string(),
+            uid: req.user().unwrap()._id.clone(),
+            name: form.name,
+            hash: hash_token(&token),
+            scopes,
+            created_at: crate::timestamp(),
+            expires_at,
+            last_used_at: None,
+            revoked: false,
+            client_id: None,
+        })
+        .await?;
+    let detail = Some(access_token.name.clone());
+    req.audit(AuditKind::TokenCreated, &access_token.uid, detail)
+        .await?;
+
+    // the token is only ever rendered here, it is not recoverable afterwards
+    render_settings(req, Some(token)).await
+}
+
+pub async fn revoke_token(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let mut res: Response = Redirect::new("/account/settings").into();
+    match req
+        .state()
+        .access_tokens()
+        .revoke(&uid, req.param("id")?)
+        .await
+    {
+        Ok(_) => res.flash_info("token revoked"),
+        Err(_) => res.flash_error("token not found"),
+    }
+    Ok(res)
+}
+
+/// Sessions of the current user, most recently used first, with the one
+/// making the request flagged as `current`.
+pub async fn active_sessions(req: &Request<State>) -> tide::Result<Vec<serde_json::Value>> {
+    let uid = &req.user().unwrap()._id;
+    let records = req.state().sessions().list(uid).await?;
+    Ok(records
+        .into_iter()
+        .map(|record| {
+            let current = record.session_id == req.session().id();
+            let mut value = json!(record);
+            value["current"] = json!(current);
+            value
+        })
+        .collect())
+}
+
+pub async fn sessions(req: Request<State>) -> tide::Result {
+    let sessions = active_sessions(&req).await?;
+    TemplateResponse::new(req, "sessions.html")
+        .with_data(json!({ "sessions": sessions }))
+        .into()
+}
 
-    // TODO: obtain shared totp secret based on authenticated user
+/// The user's own audit log, shared with the API.
+pub async fn security_events(req: &Request<State>) -> tide::Result<Vec<AuditEvent>> {
+    let filter = AuditFilter {
+        uid: Some(req.user().unwrap()._id.clone()),
+        kind: None,
+    };
+    Ok(req.state().audit_events().list(&filter, 100).await?)
+}
+
+pub async fn security(req: Request<State>) -> tide::Result {
+    let events = security_events(&req).await?;
+    TemplateResponse::new(req, "security.html")
+        .with_data(json!({ "events": events }))
+        .into()
+}
+
+pub async fn revoke_session(mut req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let record = match req.state().sessions().get(&uid, req.param("id")?).await {
+        Ok(record) => record,
+        Err(_) => {
+            let mut res: Response = Redirect::new("/account/sessions").into();
+            res.flash_error("session not found");
+            return Ok(res);
+        }
+    };
+    req.audit(AuditKind::SessionRevoked, &uid, None).await?;
+    if record.session_id == req.session().id() {
+        req.end_session().await?;
+        return Ok(Redirect::new("/").into());
+    }
+
+    req.state().destroy_session(&record).await?;
+    let mut res: Response = Redirect::new("/account/sessions").into();
+    res.flash_info("session signed out");
+    Ok(res)
+}
 
+pub async fn revoke_other_sessions(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    req.state()
+        .destroy_sessions(&uid, Some(req.session().id()))
+        .await?;
+    let detail = Some(String::from("all other sessions"));
+    req.audit(AuditKind::SessionRevoked, &uid, detail).await?;
+    let mut res: Response = Redirect::new("/account/sessions").into();
+    res.flash_info("signed out all other sessions");
+    Ok(res)
+}
+
+pub async fn request_export(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let mut res: Response = Redirect::new("/account/settings").into();
+    let jobs = req.state().jobs();
+    if let Ok(job) = jobs.latest(&uid, JobKind::Export).await {
+        if job.is_pending() {
+            res.flash_info("your data export is already being prepared");
+            return Ok(res);
+        }
+    }
+
+    jobs.insert(Job::new(JobKind::Export, &uid)).await?;
+    req.audit(AuditKind::DataExportRequested, &uid, None)
+        .await?;
+    res.flash_info("your data export is being prepared, you will get an email once it is ready");
+    Ok(res)
+}
+
+pub async fn download_export(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let job = req.state().jobs().latest(&uid, JobKind::Export).await.ok();
+    let path = job
+        .filter(|job| job.status == JobStatus::Done)
+        .filter(|job| {
+            let finished_at = job.finished_at.unwrap_or_default();
+            finished_at + EXPORT_TTL.as_secs() as usize > crate::timestamp()
+        })
+        .map(|job| export::path(req.state(), &job._id));
+    let body = match path {
+        Some(path) => Body::from_file(path).await.ok(),
+        None => None,
+    };
+    let body = match body {
+        Some(body) => body,
+        None => {
+            let mut res: Response = Redirect::new("/account/settings").into();
+            res.flash_error("there is no data export to download, request a new one");
+            return Ok(res);
+        }
+    };
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(body);
+    res.set_content_type("application/zip");
+    res.insert_header(
+        "Content-Disposition",
+        "attachment; filename=\"account-data.zip\"",
+    );
+    Ok(res)
+}
+
+pub async fn schedule_deletion(req: Request<State>) -> tide::Result {
+    let mut user = req.user().unwrap().clone();
+    let mut res: Response = Redirect::new("/account/settings").into();
+    if user.deletion_due_at.is_some() {
+        res.flash_info("your account is already scheduled for deletion");
+        return Ok(res);
+    }
+
+    let grace = req.state().config.deletion_grace().as_secs() as usize;
+    user.deletion_due_at = Some(crate::timestamp() + grace);
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::DeletionScheduled, &user._id, None)
+        .await?;
+    res.flash_info(format!(
+        "your account will be deleted in {} days, you can cancel this in your settings until then",
+        grace / (60 * 60 * 24)
+    ));
+    Ok(res)
+}
+
+pub async fn cancel_deletion(req: Request<State>) -> tide::Result {
+    let mut user = req.user().unwrap().clone();
+    let mut res: Response = Redirect::new("/account/settings").into();
+    if user.deletion_due_at.take().is_none() {
+        res.flash_error("your account is not scheduled for deletion");
+        return Ok(res);
+    }
+
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::DeletionCancelled, &user._id, None)
+        .await?;
+    res.flash_info("account deletion cancelled");
+    Ok(res)
+}
+
+pub async fn sudo(req: Request<State>) -> tide::Result {
+    TemplateResponse::new(req, "sudo.html").into()
+}
+
+pub async fn sudo_post(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_form::<SudoForm>().await {
+        Ok(form) => form,
+        Err(e) => {
+            let mut res: Response = Redirect::new("/account/sudo").into();
+            res.flash_error(e.to_string());
+            return Ok(res);
+        }
+    };
+
+    let username = req.user().unwrap().username.clone();
+    let confirmed = match req
+        .state()
+        .users()
+        .authenticate(&username, &form.password)
+        .await
+    {
+        Ok(user) if user.totp_enabled => {
+            let code = form.code.unwrap_or_default();
+            verify_second_factor(&req, &user, &code, false).await?
+        }
+        Ok(_) => SecondFactor::Accepted,
+        Err(_) => SecondFactor::Rejected,
+    };
+    match confirmed {
+        SecondFactor::Accepted => {}
+        SecondFactor::Rejected => {
+            let mut res: Response = Redirect::new("/account/sudo").into();
+            res.flash_error("invalid credentials");
+            return Ok(res);
+        }
+        SecondFactor::LockedOut => {
+            let mut res: Response = Redirect::new("/account/sudo").into();
+            res.flash_error("too many failed attempts, try again later");
+            return Ok(res);
+        }
+    }
+
+    req.sudo()?;
+    let target = req
+        .session()
+        .get::<String>("tide.sudo-redirect")
+        .and_then(|target| same_origin_path(&target))
+        .unwrap_or_else(|| String::from("/account/settings"));
+    req.session_mut().remove("tide.sudo-redirect");
+    Ok(Redirect::new(target).into())
+}
+
+pub async fn two_factor(req: Request<State>) -> tide::Result {
+    let enabled = req.user().unwrap().totp_enabled;
+    TemplateResponse::new(req, "2fa.html")
+        .with_data(json!({ "enabled": enabled }))
+        .into()
+}
+
+pub async fn update_otp(mut req: Request<State>) -> tide::Result {
+    // a new secret is only kept in the session until it has been validated,
+    // the qr code is never shown again once setup is complete
     let key_ascii = uuid::Uuid::new_v4().to_string();
-    req.session_mut().insert("tmp", key_ascii.clone()).unwrap();
+    req.session_mut().insert("tmp", key_ascii.clone())?;
 
+    let username = &req.user().ok_or(AppError::Unauthorized)?.username;
+    let (_, image) = totp_enrollment(&key_ascii, username)?;
+
+    TemplateResponse::new(req, "2fa.html")
+        .with_data(json!({ "qrcode": image }))
+        .into()
+}
+
+pub async fn disable_otp(req: Request<State>) -> tide::Result {
+    let mut user = req.user().unwrap().clone();
+    user.reset_totp();
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::TwoFactorDisabled, &user._id, None)
+        .await?;
+
+    let mut res: Response = Redirect::new("/account/settings").into();
+    res.flash_info("two factor authentication disabled");
+    Ok(res)
+}
+
+/// Builds the otpauth uri for a new totp secret along with its svg qr code.
+pub fn totp_enrollment(key_ascii: &str, username: &str) -> Result<(String, String), AppError> {
     let totp = libreauth::oath::TOTPBuilder::new()
-        .ascii_key(&key_ascii)
+        .ascii_key(key_ascii)
         .finalize()
-        .unwrap();
+        .map_err(|e| AppError::Internal(format!("invalid totp key: {:?}", e)))?;
 
-    let uri = totp
-        .key_uri_format("TwitterClone", &req.claims().unwrap().username)
-        .finalize();
+    let uri = totp.key_uri_format("TwitterClone", username).finalize();
 
-    let code = qrcode::QrCode::new(uri).unwrap();
+    let code = qrcode::QrCode::new(&uri).map_err(|e| AppError::Internal(e.to_string()))?;
     let image = code
         .render()
         .min_dimensions(200, 200)
@@ -57,34 +452,32 @@ pub async fn update_otp(mut req: Request<State>) -> tide::Result {
         .light_color(svg::Color("#f0f0f0"))
         .build();
 
-    TemplateResponse::new(req, "2fa.html")
-        .with_data(json!({ "qrcode": image }))
-        .into()
+    Ok((uri, image))
 }
 
 pub async fn validate_otp(mut req: Request<State>) -> tide::Result {
     match req.body_form::<ValidateForm>().await {
         Ok(form) => {
-            // TODO: obtain shared totp secret based on authenticated user
-            let key_ascii = req.session().get::<String>("tmp").unwrap();
-            let valid = libreauth::oath::TOTPBuilder::new()
-                .ascii_key(&key_ascii)
-                .finalize()
-                .unwrap()
-                .is_valid(&form.code);
-
-            if valid {
-                USERS.with_borrow_mut(|db| {
-                    let uid = req.claims().unwrap().uid;
-                    let mut user = req.user().unwrap().clone();
-                    user.totp_enabled = true;
-                    user.totp_secret = Some(key_ascii.clone());
-                    db.update(uid, user).unwrap();
-                });
+            let key_ascii = match req.session().get::<String>("tmp") {
+                Some(key_ascii) => key_ascii,
+                None => return Ok(Redirect::new("/account/update-2fa").into()),
+            };
+            let step = totp_step(&key_ascii, &form.code);
+
+            if let Some(step) = step {
+                let mut user = req.user().ok_or(AppError::Unauthorized)?.clone();
+                user.totp_enabled = true;
+                user.totp_secret = Some(key_ascii.clone());
+                user.totp_last_step = Some(step);
+                user.reset_totp_failures();
+                let codes = user.reset_recovery_codes();
+                let user = req.state().users().update(user).await?;
+                req.audit(AuditKind::TwoFactorEnabled, &user._id, None)
+                    .await?;
                 req.session_mut().remove("tmp");
-                let mut res: Response = Redirect::new("/account/settings").into();
-                res.flash_info("settings saved!");
-                Ok(res)
+                TemplateResponse::new(req, "recovery.html")
+                    .with_data(json!({ "codes": codes }))
+                    .into()
             } else {
                 let mut res: tide::Response = Redirect::new("/account/update-2fa").into();
                 res.flash_error("invalid code");
@@ -98,3 +491,24 @@ pub async fn validate_otp(mut req: Request<State>) -> tide::Result {
         }
     }
 }
+
+pub async fn recovery_codes(req: Request<State>) -> tide::Result {
+    TemplateResponse::new(req, "recovery.html").into()
+}
+
+pub async fn regenerate_recovery_codes(req: Request<State>) -> tide::Result {
+    let mut user = req.user().unwrap().clone();
+    if !user.totp_enabled {
+        let mut res: Response = Redirect::new("/account/settings").into();
+        res.flash_error("two factor authentication is not enabled");
+        return Ok(res);
+    }
+
+    let codes = user.reset_recovery_codes();
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::RecoveryCodesRegenerated, &user._id, None)
+        .await?;
+    TemplateResponse::new(req, "recovery.html")
+        .with_data(json!({ "codes": codes }))
+        .into()
+}
@@ -0,0 +1,293 @@
+use async_std::stream::StreamExt;
+use mongodb::{bson::doc, options::FindOptions};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tide::{Redirect, Request, Response, Server};
+use uuid::Uuid;
+
+use super::email::send_password_reset;
+use super::RoleForm;
+use crate::policy;
+use crate::prelude::*;
+use crate::ratelimit::Lockout;
+use crate::repos::audit::{AuditFilter, AuditKind, AuditRepository};
+use crate::repos::session::SessionRepository;
+use crate::repos::token::RefreshTokenRepository;
+use crate::repos::user::{Role, User, UserRepository};
+use crate::templates::TemplateResponse;
+use crate::{timestamp, State};
+
+const PAGE_SIZE: i64 = 50;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    uid: Option<String>,
+    kind: Option<String>,
+}
+
+pub fn configure(app: &mut Server<State>) {
+    let state = app.state().clone();
+    app.at("/admin")
+        .authenticated()
+        .require_role(Role::Moderator)
+        .nest({
+            let mut app = tide::with_state(state);
+            app.at("/users").require_role(Role::Admin).get(users);
+            app.at("/users/:id").require_role(Role::Admin).get(user);
+            app.at("/users/:id/suspend")
+                .require_role(Role::Admin)
+                .post(suspend);
+            app.at("/users/:id/unsuspend")
+                .require_role(Role::Admin)
+                .post(unsuspend);
+            app.at("/users/:id/reset-password")
+                .require_role(Role::Admin)
+                .post(reset_password);
+            app.at("/users/:id/reset-2fa")
+                .require_role(Role::Admin)
+                .post(reset_two_factor);
+            app.at("/users/:id/role")
+                .require_role(Role::Admin)
+                .post(change_role);
+            app.at("/audit").require_role(Role::Admin).get(audit);
+            app.at("/lockouts").require_role(Role::Admin).get(lockouts);
+            super::reports::configure(&mut app);
+            app
+        });
+}
+
+/// What the console shows of an account, leaving out the password and
+/// second factor secrets.
+fn summary(user: &User) -> Value {
+    json!({
+        "id": user._id,
+        "username": user.username,
+        "role": user.role,
+        "email_verified": user.email_verified,
+        "totp_enabled": user.totp_enabled,
+        "totp_locked": user.is_totp_locked(),
+        "created_at": user.created_at,
+        "last_login_at": user.last_login_at,
+        "suspended_at": user.suspended_at,
+    })
+}
+
+fn user_page(id: &str) -> String {
+    format!("/admin/users/{}", id)
+}
+
+pub async fn users(req: Request<State>) -> tide::Result {
+    let query = req.query::<SearchQuery>()?.q.unwrap_or_default();
+    let users = req.state().users().search(&query, PAGE_SIZE).await?;
+    let users: Vec<Value> = users.iter().map(summary).collect();
+    TemplateResponse::new(req, "admin/users.html")
+        .with_data(json!({ "query": query, "users": users }))
+        .into()
+}
+
+pub async fn user(req: Request<State>) -> tide::Result {
+    let user = match req.state().users().get_by_id(req.param("id")?).await {
+        Ok(user) => user,
+        Err(_) => return rejected("user not found"),
+    };
+    let sessions = req.state().sessions().list(&user._id).await?.len();
+    let events = req
+        .state()
+        .audit_events()
+        .list(
+            &AuditFilter {
+                uid: Some(user._id.clone()),
+                kind: None,
+            },
+            PAGE_SIZE,
+        )
+        .await?;
+    let can_administer = policy::can_administer(req.user().unwrap(), &user);
+    TemplateResponse::new(req, "admin/user.html")
+        .with_data(json!({
+            "user": summary(&user),
+            "sessions": sessions,
+            "events": events,
+            "can_administer": can_administer,
+            "roles": [Role::User, Role::Moderator, Role::Admin],
+        }))
+        .into()
+}
+
+/// Loads the account named in the url if the admin may act on it.
+async fn target(req: &Request<State>) -> tide::Result<Result<User, &'static str>> {
+    match req.state().users().get_by_id(req.param("id")?).await {
+        Ok(user) if policy::can_administer(req.user().unwrap(), &user) => Ok(Ok(user)),
+        Ok(_) => Ok(Err("you can't change your own account here")),
+        Err(_) => Ok(Err("user not found")),
+    }
+}
+
+fn rejected(message: &str) -> tide::Result {
+    let mut res: Response = Redirect::new("/admin/users").into();
+    res.flash_error(message);
+    Ok(res)
+}
+
+/// Signs the user out everywhere and keeps them from logging back in until
+/// they are unsuspended.
+pub async fn suspend_user(state: &State, mut user: User) -> tide::Result<User> {
+    user.suspended_at = Some(timestamp());
+    let user = state.users().update(user).await?;
+    state.refresh_tokens().revoke_all(&user._id).await?;
+    state.destroy_sessions(&user._id, None).await?;
+    Ok(user)
+}
+
+pub async fn suspend(req: Request<State>) -> tide::Result {
+    let user = match target(&req).await? {
+        Ok(user) => user,
+        Err(message) => return rejected(message),
+    };
+    let mut res: Response = Redirect::new(user_page(&user._id)).into();
+    if user.is_suspended() {
+        res.flash_error("account is already suspended");
+        return Ok(res);
+    }
+    let user = suspend_user(req.state(), user).await?;
+    req.audit(AuditKind::Suspended, &user._id, None).await?;
+    res.flash_info("account suspended");
+    Ok(res)
+}
+
+pub async fn unsuspend(req: Request<State>) -> tide::Result {
+    let mut user = match target(&req).await? {
+        Ok(user) => user,
+        Err(message) => return rejected(message),
+    };
+    let mut res: Response = Redirect::new(user_page(&user._id)).into();
+    if !user.is_suspended() {
+        res.flash_error("account is not suspended");
+        return Ok(res);
+    }
+    user.suspended_at = None;
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::Unsuspended, &user._id, None).await?;
+    res.flash_info("account unsuspended");
+    Ok(res)
+}
+
+/// Replaces the password with a random one nobody knows, signs the user out
+/// and emails them a link to choose a new one.
+pub async fn reset_password(req: Request<State>) -> tide::Result {
+    let mut user = match target(&req).await? {
+        Ok(user) => user,
+        Err(message) => return rejected(message),
+    };
+    user.set_password(&Uuid::new_v4().simple().to_string())?;
+    let user = req.state().users().update(user).await?;
+    req.state().refresh_tokens().revoke_all(&user._id).await?;
+    req.state().destroy_sessions(&user._id, None).await?;
+    req.audit(AuditKind::PasswordResetForced, &user._id, None)
+        .await?;
+
+    let mut res: Response = Redirect::new(user_page(&user._id)).into();
+    match send_password_reset(req.state(), &user).await {
+        Ok(_) => res.flash_info("password reset, the user has been emailed a reset link"),
+        Err(e) => {
+            tide::log::error!("failed to send password reset email: {}", e);
+            res.flash_error("password reset, but the reset email could not be sent");
+        }
+    }
+    Ok(res)
+}
+
+pub async fn reset_two_factor(req: Request<State>) -> tide::Result {
+    let mut user = match target(&req).await? {
+        Ok(user) => user,
+        Err(message) => return rejected(message),
+    };
+    user.reset_totp();
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::TwoFactorReset, &user._id, None)
+        .await?;
+
+    let mut res: Response = Redirect::new(user_page(&user._id)).into();
+    res.flash_info("two factor authentication turned off");
+    Ok(res)
+}
+
+pub async fn change_role(mut req: Request<State>) -> tide::Result {
+    let form = req.body_form::<RoleForm>().await;
+    let mut user = match target(&req).await? {
+        Ok(user) => user,
+        Err(message) => return rejected(message),
+    };
+    let mut res: Response = Redirect::new(user_page(&user._id)).into();
+    let role = match form {
+        Ok(form) => form.role,
+        Err(e) => {
+            res.flash_error(e.to_string());
+            return Ok(res);
+        }
+    };
+    if role == user.role {
+        return Ok(res);
+    }
+
+    let detail = format!("{} to {}", user.role.as_str(), role.as_str());
+    user.role = role;
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::RoleChanged, &user._id, Some(detail))
+        .await?;
+    res.flash_info("role changed");
+    Ok(res)
+}
+
+pub async fn audit(req: Request<State>) -> tide::Result {
+    let query = req.query::<AuditQuery>()?;
+    let filter = AuditFilter {
+        uid: query.uid.filter(|uid| !uid.is_empty()),
+        // an empty or unknown kind shows every kind
+        kind: query
+            .kind
+            .and_then(|kind| serde_json::from_value(Value::String(kind)).ok()),
+    };
+    let events = req
+        .state()
+        .audit_events()
+        .list(&filter, PAGE_SIZE * 2)
+        .await?;
+    TemplateResponse::new(req, "admin/audit.html")
+        .with_data(json!({
+            "uid": filter.uid,
+            "kind": filter.kind,
+            "kinds": AuditKind::ALL,
+            "events": events,
+        }))
+        .into()
+}
+
+/// Recent lockouts by the login rate limiter, newest first.
+pub async fn lockouts(req: Request<State>) -> tide::Result {
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .limit(PAGE_SIZE * 2)
+        .build();
+    let mut cursor = req.state().lockouts().find(None, options).await?;
+    let now = timestamp();
+    let mut lockouts = Vec::new();
+    while let Some(lockout) = cursor.next().await {
+        let lockout: Lockout = lockout?;
+        lockouts.push(json!({
+            "kind": lockout.kind,
+            "value": lockout.value,
+            "created_at": lockout.created_at,
+            "locked_until": lockout.locked_until,
+            "active": lockout.locked_until > now,
+        }));
+    }
+    TemplateResponse::new(req, "admin/lockouts.html")
+        .with_data(json!({ "lockouts": lockouts }))
+        .into()
+}
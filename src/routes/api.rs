@@ -0,0 +1,34 @@
+use tide::{Request, Server};
+
+use crate::json::ApiError;
+use crate::registry::State;
+
+mod account;
+mod auth;
+mod email;
+mod follows;
+mod notifications;
+mod posts;
+mod reports;
+mod token;
+
+pub fn configure(app: &mut Server<State>) {
+    let state = app.state().clone();
+    app.at("/api/v1").nest({
+        let mut api = tide::with_state(state);
+        auth::configure(&mut api);
+        email::configure(&mut api);
+        token::configure(&mut api);
+        account::configure(&mut api);
+        posts::configure(&mut api);
+        follows::configure(&mut api);
+        notifications::configure(&mut api);
+        reports::configure(&mut api);
+        api.at("*").all(not_found);
+        api
+    });
+}
+
+pub async fn not_found(_req: Request<State>) -> tide::Result {
+    ApiError::not_found("no such endpoint").into()
+}
@@ -0,0 +1,266 @@
+use async_std::io::ReadExt;
+use serde_json::{json, Value};
+use tide::{Request, Server, StatusCode};
+
+use crate::error::AppError;
+use crate::jobs::import;
+use crate::json::{ApiError, JsonResponse};
+use crate::prelude::*;
+use crate::repos::audit::AuditKind;
+use crate::repos::job::{Job, JobKind, JobRepository};
+use crate::repos::session::SessionRepository;
+use crate::repos::token::Scope;
+use crate::repos::user::{Profile, UserRepository};
+use crate::routes::account::{active_sessions, security_events, totp_enrollment};
+use crate::routes::oauth::{authorized_apps, revoke_grant};
+use crate::routes::{totp_step, verify_second_factor, SecondFactor, SudoForm, ValidateForm};
+use crate::State;
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/account/profile").scope(Scope::Read).get(profile);
+    api.at("/account/sudo")
+        .api_authenticated()
+        .rate_limited_by_account()
+        .post(sudo);
+    api.at("/account/2fa")
+        .api_authenticated()
+        .sudo()
+        .post(update_otp)
+        .delete(disable_otp);
+    api.at("/account/2fa/validate")
+        .api_authenticated()
+        .sudo()
+        .post(validate_otp);
+    api.at("/account/recovery-codes")
+        .api_authenticated()
+        .sudo()
+        .post(regenerate_recovery_codes);
+    api.at("/account/authorized-apps")
+        .scope(Scope::Read)
+        .get(list_authorized_apps);
+    api.at("/account/authorized-apps/:id/revoke")
+        .api_authenticated()
+        .post(revoke_authorized_app);
+    api.at("/account/sessions").scope(Scope::Read).get(sessions);
+    api.at("/account/security-events")
+        .scope(Scope::Read)
+        .get(list_security_events);
+    api.at("/account/sessions/revoke-others")
+        .api_authenticated()
+        .post(revoke_other_sessions);
+    api.at("/account/sessions/:id/revoke")
+        .api_authenticated()
+        .post(revoke_session);
+    api.at("/account/import")
+        .scope(Scope::Read)
+        .get(import_status);
+    api.at("/account/import")
+        .scope(Scope::WritePosts)
+        .post(import_archive);
+}
+
+fn import_progress(job: &Job) -> Value {
+    json!({
+        "id": job._id,
+        "status": job.status,
+        "imported": job.checkpoint,
+        "total": job.total,
+    })
+}
+
+pub async fn import_status(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    match req.state().jobs().latest(&uid, JobKind::Import).await {
+        Ok(job) => JsonResponse::new(import_progress(&job)).into(),
+        Err(_) => ApiError::not_found("no import was started").into(),
+    }
+}
+
+/// Takes the zip of a Twitter archive as the request body and queues its
+/// import.
+pub async fn import_archive(mut req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    if req
+        .len()
+        .is_some_and(|len| len as u64 > import::MAX_ARCHIVE_SIZE)
+    {
+        return archive_too_large();
+    }
+    let jobs = req.state().jobs();
+    if let Ok(job) = jobs.latest(&uid, JobKind::Import).await {
+        if job.is_pending() {
+            return ApiError::conflict("an import is already running").into();
+        }
+    }
+
+    let job = Job::new(JobKind::Import, &uid);
+    async_std::fs::create_dir_all(&req.state().config.import_dir).await?;
+    let path = import::path(req.state(), &job._id);
+    let mut file = async_std::fs::File::create(&path).await?;
+    let body = req.take_body().take(import::MAX_ARCHIVE_SIZE + 1);
+    if async_std::io::copy(body, &mut file).await? > import::MAX_ARCHIVE_SIZE {
+        drop(file);
+        async_std::fs::remove_file(&path).await?;
+        return archive_too_large();
+    }
+    file.sync_all().await?;
+    let job = jobs.insert(job).await?;
+    req.audit(AuditKind::DataImportRequested, &uid, None)
+        .await?;
+    JsonResponse::new(import_progress(&job))
+        .with_status(StatusCode::Accepted)
+        .into()
+}
+
+fn archive_too_large() -> tide::Result {
+    ApiError::new(
+        StatusCode::PayloadTooLarge,
+        "payload_too_large",
+        format!(
+            "archives can be at most {} MiB",
+            import::MAX_ARCHIVE_SIZE >> 20
+        ),
+    )
+    .into()
+}
+
+pub async fn list_authorized_apps(req: Request<State>) -> tide::Result {
+    JsonResponse::new(authorized_apps(&req).await?).into()
+}
+
+pub async fn revoke_authorized_app(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    if revoke_grant(req.state(), &uid, req.param("id")?).await? {
+        Ok(tide::Response::new(StatusCode::NoContent))
+    } else {
+        ApiError::not_found("app not found").into()
+    }
+}
+
+pub async fn list_security_events(req: Request<State>) -> tide::Result {
+    JsonResponse::new(security_events(&req).await?).into()
+}
+
+pub async fn sessions(req: Request<State>) -> tide::Result {
+    JsonResponse::new(active_sessions(&req).await?).into()
+}
+
+pub async fn revoke_session(mut req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let record = match req.state().sessions().get(&uid, req.param("id")?).await {
+        Ok(record) => record,
+        Err(_) => return ApiError::not_found("session not found").into(),
+    };
+    req.audit(AuditKind::SessionRevoked, &uid, None).await?;
+    if record.session_id == req.session().id() {
+        req.end_session().await?;
+    } else {
+        req.state().destroy_session(&record).await?;
+    }
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+pub async fn revoke_other_sessions(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    req.state()
+        .destroy_sessions(&uid, Some(req.session().id()))
+        .await?;
+    let detail = Some(String::from("all other sessions"));
+    req.audit(AuditKind::SessionRevoked, &uid, detail).await?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+pub async fn profile(req: Request<State>) -> tide::Result {
+    JsonResponse::new(Profile::from(req.user().unwrap())).into()
+}
+
+pub async fn sudo(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<SudoForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    let username = req.user().unwrap().username.clone();
+    match req
+        .state()
+        .users()
+        .authenticate(&username, &form.password)
+        .await
+    {
+        Ok(user) if user.totp_enabled => {
+            let code = form.code.unwrap_or_default();
+            match verify_second_factor(&req, &user, &code, false).await? {
+                SecondFactor::Accepted => {}
+                SecondFactor::Rejected => return ApiError::unauthorized("invalid otp").into(),
+                SecondFactor::LockedOut => return ApiError::too_many_requests().into(),
+            }
+        }
+        Ok(_) => {}
+        Err(_) => return ApiError::unauthorized("invalid credentials").into(),
+    }
+
+    req.sudo()?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+pub async fn update_otp(mut req: Request<State>) -> tide::Result {
+    let key_ascii = uuid::Uuid::new_v4().to_string();
+    req.session_mut().insert("tmp", key_ascii.clone())?;
+
+    let username = &req.user().ok_or(AppError::Unauthorized)?.username;
+    let (uri, image) = totp_enrollment(&key_ascii, username)?;
+    JsonResponse::new(json!({ "uri": uri, "qrcode": image })).into()
+}
+
+pub async fn validate_otp(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<ValidateForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+    let key_ascii = match req.session().get::<String>("tmp") {
+        Some(key_ascii) => key_ascii,
+        None => return ApiError::conflict("two factor setup has not been started").into(),
+    };
+    let step = match totp_step(&key_ascii, &form.code) {
+        Some(step) => step,
+        None => return ApiError::unprocessable("invalid code").into(),
+    };
+
+    let mut user = req.user().unwrap().clone();
+    user.totp_enabled = true;
+    user.totp_secret = Some(key_ascii);
+    user.totp_last_step = Some(step);
+    user.reset_totp_failures();
+    let codes = user.reset_recovery_codes();
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::TwoFactorEnabled, &user._id, None)
+        .await?;
+    req.session_mut().remove("tmp");
+    JsonResponse::new(json!({
+        "user": Profile::from(&user),
+        "recovery_codes": codes,
+    }))
+    .into()
+}
+
+pub async fn disable_otp(req: Request<State>) -> tide::Result {
+    let mut user = req.user().unwrap().clone();
+    user.reset_totp();
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::TwoFactorDisabled, &user._id, None)
+        .await?;
+    JsonResponse::new(Profile::from(&user)).into()
+}
+
+pub async fn regenerate_recovery_codes(req: Request<State>) -> tide::Result {
+    let mut user = req.user().unwrap().clone();
+    if !user.totp_enabled {
+        return ApiError::conflict("two factor authentication is not enabled").into();
+    }
+
+    let codes = user.reset_recovery_codes();
+    let user = req.state().users().update(user).await?;
+    req.audit(AuditKind::RecoveryCodesRegenerated, &user._id, None)
+        .await?;
+    JsonResponse::new(json!({ "recovery_codes": codes })).into()
+}
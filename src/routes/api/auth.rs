@@ -0,0 +1,118 @@
+use serde_json::json;
+use tide::{Request, Server, StatusCode};
+use validator::Validate;
+
+use crate::csrf;
+use crate::error::AppError;
+use crate::json::{ApiError, JsonResponse};
+use crate::prelude::*;
+use crate::repos::user::{Profile, User, UserRepository};
+use crate::routes::email::send_verification;
+use crate::routes::{
+    audit_failed_login, verify_second_factor, SecondFactor, UserCreateForm, UserForm, ValidateForm,
+};
+use crate::{Claims, State};
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/auth/register").post(register);
+    api.at("/auth/login").rate_limited().post(login);
+    api.at("/auth/otp").post(otp);
+    api.at("/auth/logout").post(logout);
+    api.at("/auth/csrf").get(csrf_token);
+}
+
+/// Token to send as `X-CSRF-Token` when calling the API with the session
+/// cookie instead of a bearer token.
+pub async fn csrf_token(mut req: Request<State>) -> tide::Result {
+    let token = csrf::issue(&mut req)?;
+    JsonResponse::new(json!({ "csrf_token": token })).into()
+}
+
+pub async fn register(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<UserCreateForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+    if let Err(e) = form.validate() {
+        return ApiError::validation(&e).into();
+    }
+
+    let user = User::new(form.username, &form.password)?;
+    match req.state().users().insert(user).await {
+        Ok(user) => {
+            if let Err(e) = send_verification(req.state(), &user).await {
+                tide::log::error!("failed to send verification email: {}", e);
+            }
+            JsonResponse::new(Profile::from(&user))
+                .with_status(StatusCode::Created)
+                .into()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            ApiError::conflict("username is already taken").into()
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn login(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<UserForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    let users = req.state().users();
+    match users.authenticate(&form.username, &form.password).await {
+        Ok(user) => {
+            let claims = Claims::new(&user, req.state().config.session_lifetime());
+            req.start_session(claims).await?;
+            JsonResponse::new(json!({
+                "user": Profile::from(&user),
+                "totp_required": user.totp_enabled,
+            }))
+            .into()
+        }
+        Err(e) => {
+            audit_failed_login(&req, &form.username, &e).await;
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                ApiError::forbidden("this account has been suspended").into()
+            } else {
+                ApiError::unauthorized("invalid credentials").into()
+            }
+        }
+    }
+}
+
+pub async fn otp(mut req: Request<State>) -> tide::Result {
+    if !req.is_authenticated().await {
+        return ApiError::unauthorized("authentication required").into();
+    } else if !req.requires_totp() {
+        return ApiError::conflict("two factor authentication is not pending").into();
+    }
+
+    let form = match req.body_json::<ValidateForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    let user = req.user().ok_or(AppError::Unauthorized)?.clone();
+    let mut claims = req.claims().ok_or(AppError::Unauthorized)?;
+    claims.totp_attempt += 1;
+    match verify_second_factor(&req, &user, &form.code, true).await? {
+        SecondFactor::Accepted => {
+            claims.totp = Some(claims.exp);
+            req.login(claims)?;
+            JsonResponse::new(Profile::from(&user)).into()
+        }
+        SecondFactor::Rejected => {
+            claims.totp = None;
+            req.login(claims)?;
+            ApiError::unauthorized("invalid otp").into()
+        }
+        SecondFactor::LockedOut => ApiError::too_many_requests().into(),
+    }
+}
+
+pub async fn logout(mut req: Request<State>) -> tide::Result {
+    req.end_session().await?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
@@ -0,0 +1,79 @@
+use tide::{Request, Server, StatusCode};
+use validator::Validate;
+
+use crate::json::{ApiError, JsonResponse};
+use crate::jwt::Purpose;
+use crate::prelude::*;
+use crate::repos::user::{Profile, UserRepository};
+use crate::routes::email::{redeem_token, send_password_reset, send_verification, update_password};
+use crate::routes::{EmailTokenForm, ForgotPasswordForm, ResetPasswordForm};
+use crate::State;
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/auth/verify-email").post(verify_email);
+    api.at("/auth/forgot-password")
+        .rate_limited()
+        .post(forgot_password);
+    api.at("/auth/reset-password/:token").post(reset_password);
+    api.at("/account/resend-verification")
+        .api_authenticated()
+        .post(resend_verification);
+}
+
+pub async fn verify_email(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<EmailTokenForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    match redeem_token(req.state(), &form.token, Purpose::VerifyEmail).await? {
+        Some(mut user) => {
+            user.email_verified = true;
+            let user = req.state().users().update(user).await?;
+            JsonResponse::new(Profile::from(&user)).into()
+        }
+        None => ApiError::not_found("token is invalid or has expired").into(),
+    }
+}
+
+pub async fn resend_verification(req: Request<State>) -> tide::Result {
+    let user = req.user().unwrap().clone();
+    if user.email_verified {
+        return ApiError::conflict("email address is already verified").into();
+    }
+
+    send_verification(req.state(), &user).await?;
+    Ok(tide::Response::new(StatusCode::Accepted))
+}
+
+pub async fn forgot_password(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<ForgotPasswordForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    // the response is the same whether or not the account exists
+    if let Ok(user) = req.state().users().get_by_username(&form.username).await {
+        send_password_reset(req.state(), &user).await?;
+    }
+    Ok(tide::Response::new(StatusCode::Accepted))
+}
+
+pub async fn reset_password(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<ResetPasswordForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+    if let Err(e) = form.validate() {
+        return ApiError::validation(&e).into();
+    }
+
+    let token = req.param("token")?.to_string();
+    match redeem_token(req.state(), &token, Purpose::ResetPassword).await? {
+        Some(user) => {
+            let user = update_password(&req, user, form.password).await?;
+            JsonResponse::new(Profile::from(&user)).into()
+        }
+        None => ApiError::not_found("token is invalid or has expired").into(),
+    }
+}
@@ -0,0 +1,71 @@
+use serde_json::json;
+use tide::{Request, Server, StatusCode};
+
+use crate::json::{ApiError, JsonResponse};
+use crate::prelude::*;
+use crate::repos::follow::{Follow, FollowRepository};
+use crate::repos::notification::{Notification, NotificationKind, NotificationRepository};
+use crate::repos::token::Scope;
+use crate::repos::user::UserRepository;
+use crate::State;
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/users/:id/follow")
+        .api_authenticated()
+        .verified()
+        .post(follow)
+        .delete(unfollow);
+    api.at("/users/:id/following")
+        .scope(Scope::Read)
+        .get(following);
+    api.at("/users/:id/followers")
+        .scope(Scope::Read)
+        .get(followers);
+}
+
+pub async fn follow(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let followee = req.param("id")?.to_string();
+    if followee == uid {
+        return ApiError::unprocessable("you can't follow yourself").into();
+    }
+    match req.state().users().get_by_id(&followee).await {
+        Ok(user) if !user.is_deleted() => {}
+        _ => return ApiError::not_found("account not found").into(),
+    }
+
+    if req
+        .state()
+        .follows()
+        .follow(&Follow::new(&uid, &followee))
+        .await?
+    {
+        let notification = Notification::new(&followee, NotificationKind::Followed, &uid, None);
+        req.state().notifications().insert(notification).await?;
+    }
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+pub async fn unfollow(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    if req
+        .state()
+        .follows()
+        .unfollow(&uid, req.param("id")?)
+        .await?
+    {
+        Ok(tide::Response::new(StatusCode::NoContent))
+    } else {
+        ApiError::not_found("you don't follow this account").into()
+    }
+}
+
+pub async fn following(req: Request<State>) -> tide::Result {
+    let uids = req.state().follows().following(req.param("id")?).await?;
+    JsonResponse::new(json!({ "following": uids })).into()
+}
+
+pub async fn followers(req: Request<State>) -> tide::Result {
+    let uids = req.state().follows().followers(req.param("id")?).await?;
+    JsonResponse::new(json!({ "followers": uids })).into()
+}
@@ -0,0 +1,29 @@
+use serde_json::json;
+use tide::{Request, Server, StatusCode};
+
+use crate::json::JsonResponse;
+use crate::prelude::*;
+use crate::repos::notification::NotificationRepository;
+use crate::repos::token::Scope;
+use crate::State;
+
+const PAGE_SIZE: i64 = 100;
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/notifications").scope(Scope::Read).get(list);
+    api.at("/notifications/read")
+        .api_authenticated()
+        .post(mark_read);
+}
+
+pub async fn list(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let notifications = req.state().notifications().list(&uid, PAGE_SIZE).await?;
+    JsonResponse::new(json!({ "notifications": notifications })).into()
+}
+
+pub async fn mark_read(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    req.state().notifications().mark_read(&uid).await?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
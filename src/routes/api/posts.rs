@@ -0,0 +1,179 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tide::{Request, Server, StatusCode};
+use validator::Validate;
+
+use crate::json::{ApiError, JsonResponse};
+use crate::policy;
+use crate::prelude::*;
+use crate::repos::follow::FollowRepository;
+use crate::repos::notification::{Notification, NotificationKind, NotificationRepository};
+use crate::repos::post::{Post, PostRepository};
+use crate::repos::token::Scope;
+use crate::repos::user::UserRepository;
+use crate::State;
+
+const PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize, Validate)]
+pub struct PostForm {
+    #[validate(length(
+        min = 1,
+        max = 500,
+        code = "length",
+        message = "Post must be between 1 and 500 characters"
+    ))]
+    body: String,
+    in_reply_to: Option<String>,
+}
+
+/// Pages through posts newest first, `before` is the `created_at` of the
+/// last post of the previous page.
+#[derive(Deserialize)]
+pub struct PageQuery {
+    before: Option<usize>,
+    limit: Option<i64>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/posts")
+        .scope(Scope::WritePosts)
+        .verified()
+        .post(create);
+    api.at("/posts/:id").scope(Scope::Read).get(get);
+    api.at("/posts/:id").scope(Scope::WritePosts).delete(delete);
+    api.at("/users/:id/posts").scope(Scope::Read).get(by_user);
+    api.at("/timeline").scope(Scope::Read).get(timeline);
+}
+
+pub fn post_json(post: &Post) -> Value {
+    json!({
+        "id": post._id,
+        "uid": post.uid,
+        "body": post.body,
+        "created_at": post.created_at,
+        "in_reply_to": post.in_reply_to,
+        "imported": post.imported_from.is_some(),
+        "media": post.media,
+    })
+}
+
+fn page(posts: Vec<Post>) -> tide::Result {
+    let posts: Vec<Value> = posts.iter().map(post_json).collect();
+    JsonResponse::new(json!({ "posts": posts })).into()
+}
+
+pub async fn create(mut req: Request<State>) -> tide::Result {
+    let mut form = match req.body_json::<PostForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+    form.body = form.body.trim().to_string();
+    if let Err(e) = form.validate() {
+        return ApiError::validation(&e).into();
+    }
+
+    let uid = req.user().unwrap()._id.clone();
+    let state = req.state();
+    let parent = match form.in_reply_to.as_deref() {
+        Some(id) => match state.posts().get(id).await {
+            Ok(parent) => Some(parent),
+            Err(_) => return ApiError::not_found("the post replied to was not found").into(),
+        },
+        None => None,
+    };
+
+    let post = state
+        .posts()
+        .insert(Post::new(&uid, &form.body, form.in_reply_to))
+        .await?;
+    if let Some(parent) = parent.filter(|parent| parent.uid != uid) {
+        let notification = Notification::new(
+            &parent.uid,
+            NotificationKind::Replied,
+            &uid,
+            Some(post._id.clone()),
+        );
+        state.notifications().insert(notification).await?;
+    }
+    JsonResponse::new(post_json(&post))
+        .with_status(StatusCode::Created)
+        .into()
+}
+
+pub async fn get(req: Request<State>) -> tide::Result {
+    match req.state().posts().get(req.param("id")?).await {
+        Ok(post) => JsonResponse::new(post_json(&post)).into(),
+        Err(_) => ApiError::not_found("post not found").into(),
+    }
+}
+
+pub async fn delete(req: Request<State>) -> tide::Result {
+    let posts = req.state().posts();
+    let post = match posts.get(req.param("id")?).await {
+        Ok(post) => post,
+        Err(_) => return ApiError::not_found("post not found").into(),
+    };
+    if !policy::can_manage(req.user().unwrap(), &post) {
+        return ApiError::forbidden("you can't delete this post").into();
+    }
+    posts.remove(&post._id).await?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+pub async fn by_user(req: Request<State>) -> tide::Result {
+    let query: PageQuery = req.query()?;
+    let uid = req.param("id")?.to_string();
+    match req.state().users().get_by_id(&uid).await {
+        Ok(user) if !user.is_deleted() => {}
+        _ => return ApiError::not_found("account not found").into(),
+    }
+    let posts = req
+        .state()
+        .posts()
+        .feed(&[uid], query.before, query.limit())
+        .await?;
+    page(posts)
+}
+
+/// Posts of the accounts the user follows and their own.
+pub async fn timeline(req: Request<State>) -> tide::Result {
+    let query: PageQuery = req.query()?;
+    let uid = req.user().unwrap()._id.clone();
+    let mut uids = req.state().follows().following(&uid).await?;
+    uids.push(uid);
+    let posts = req
+        .state()
+        .posts()
+        .feed(&uids, query.before, query.limit())
+        .await?;
+    page(posts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PageQuery, MAX_PAGE_SIZE, PAGE_SIZE};
+
+    #[test]
+    fn clamps_the_page_size() {
+        let limit = |limit| {
+            PageQuery {
+                before: None,
+                limit,
+            }
+            .limit()
+        };
+        assert_eq!(limit(None), PAGE_SIZE);
+        assert_eq!(limit(Some(0)), 1);
+        assert_eq!(limit(Some(-5)), 1);
+        assert_eq!(limit(Some(10)), 10);
+        assert_eq!(limit(Some(10_000)), MAX_PAGE_SIZE);
+    }
+}
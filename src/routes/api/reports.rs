@@ -0,0 +1,39 @@
+use std::io::ErrorKind;
+
+use tide::{Request, Server, StatusCode};
+use validator::Validate;
+
+use crate::json::ApiError;
+use crate::prelude::*;
+use crate::routes::reports::file_report;
+use crate::routes::ReportForm;
+use crate::State;
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/reports").api_authenticated().post(report);
+}
+
+/// Files a report. Only the fact that it was received is returned, reporters
+/// don't get to see what others said.
+pub async fn report(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<ReportForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+    if let Err(e) = form.validate() {
+        return ApiError::validation(&e).into();
+    }
+
+    let uid = req.user().unwrap()._id.clone();
+    match file_report(req.state(), &uid, form).await {
+        Ok(_) => Ok(tide::Response::new(StatusCode::Accepted)),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            ApiError::conflict("you have already reported this").into()
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            ApiError::unprocessable(e.to_string()).into()
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => ApiError::not_found(e.to_string()).into(),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tide::{Request, Server, StatusCode};
+use uuid::Uuid;
+
+use crate::json::{ApiError, JsonResponse};
+use crate::prelude::*;
+use crate::repos::audit::AuditKind;
+use crate::repos::token::{hash_token, RefreshToken, RefreshTokenRepository};
+use crate::repos::user::{User, UserRepository};
+use crate::routes::{audit_failed_login, verify_second_factor, SecondFactor};
+use crate::{timestamp, Claims, State};
+
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(60 * 15);
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenForm {
+    username: String,
+    password: String,
+    code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshForm {
+    refresh_token: String,
+}
+
+pub fn configure(api: &mut Server<State>) {
+    api.at("/auth/token").rate_limited().post(token);
+    api.at("/auth/token/refresh").post(refresh);
+    api.at("/auth/token/revoke").post(revoke);
+}
+
+pub async fn token(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<TokenForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    let users = req.state().users();
+    let user = match users.authenticate(&form.username, &form.password).await {
+        Ok(user) => user,
+        Err(e) => {
+            audit_failed_login(&req, &form.username, &e).await;
+            return ApiError::unauthorized("invalid credentials").into();
+        }
+    };
+    if user.totp_enabled {
+        let code = match form.code {
+            Some(code) => code,
+            None => return ApiError::forbidden("two factor code required").into(),
+        };
+        match verify_second_factor(&req, &user, &code, true).await? {
+            SecondFactor::Accepted => {}
+            SecondFactor::Rejected => return ApiError::unauthorized("invalid otp").into(),
+            SecondFactor::LockedOut => return ApiError::too_many_requests().into(),
+        }
+    }
+
+    req.audit(
+        AuditKind::LoginSucceeded,
+        &user._id,
+        Some(String::from("token")),
+    )
+    .await?;
+    issue_tokens(&req, &user).await
+}
+
+pub async fn refresh(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<RefreshForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    // refresh tokens are single use, every refresh rotates to a new one
+    let hash = hash_token(&form.refresh_token);
+    let stored = match req.state().refresh_tokens().take(&hash).await {
+        Ok(stored) => stored,
+        Err(_) => return ApiError::unauthorized("invalid refresh token").into(),
+    };
+    let user = match req.state().users().get_by_id(&stored.uid).await {
+        Ok(user) => user,
+        Err(_) => return ApiError::unauthorized("invalid refresh token").into(),
+    };
+    issue_tokens(&req, &user).await
+}
+
+pub async fn revoke(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_json::<RefreshForm>().await {
+        Ok(form) => form,
+        Err(e) => return ApiError::unprocessable(e.to_string()).into(),
+    };
+
+    req.state()
+        .refresh_tokens()
+        .revoke(&hash_token(&form.refresh_token))
+        .await?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+async fn issue_tokens(req: &Request<State>, user: &User) -> tide::Result {
+    let mut claims = Claims::new(user, ACCESS_TOKEN_TTL);
+    if user.totp_enabled {
+        claims.totp = Some(claims.exp);
+    }
+    let access_token = req.state().keys.sign(&claims)?;
+
+    let refresh_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let now = timestamp();
+    req.state()
+        .refresh_tokens()
+        .insert(RefreshToken {
+            _id: hash_token(&refresh_token),
+            uid: user._id.clone(),
+            created_at: now,
+            expires_at: now + REFRESH_TOKEN_TTL.as_secs() as usize,
+            revoked: false,
+        })
+        .await?;
+
+    JsonResponse::new(json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": ACCESS_TOKEN_TTL.as_secs(),
+        "refresh_token": refresh_token,
+    }))
+    .into()
+}
@@ -1,18 +1,22 @@
+use std::io::ErrorKind;
+
 use tide::{Redirect, Request, Server};
-use uuid::Uuid;
 use validator::Validate;
 
-use super::{UserCreateForm, UserForm, ValidateForm};
+use super::email::send_verification;
+use super::{
+    audit_failed_login, verify_second_factor, SecondFactor, UserCreateForm, UserForm, ValidateForm,
+};
+use crate::error::AppError;
+use crate::forms::FormState;
 use crate::prelude::*;
-use crate::registry::USERS;
-use crate::repos::user::{User, UserStore};
-use crate::repos::*;
+use crate::repos::user::{User, UserRepository};
 use crate::templates::TemplateResponse;
 use crate::{Claims, State};
 
 pub fn configure(app: &mut Server<State>) {
     app.at("/register").get(register).post(register_post);
-    app.at("/login").post(authenticate);
+    app.at("/login").rate_limited().post(authenticate);
     app.at("/otp").post(authenticate_otp);
 }
 
@@ -21,62 +25,68 @@ pub async fn register(req: Request<State>) -> tide::Result {
 }
 
 pub async fn register_post(mut req: Request<State>) -> tide::Result {
-    match req.body_form::<UserCreateForm>().await {
-        Ok(form) => match form.validate() {
-            Ok(_) => {
-                let res: tide::Response = Redirect::new("/").into();
-                match USERS.with_borrow_mut(|db| {
-                    db.insert(User {
-                        _id: Uuid::new_v4().to_string(),
-                        username: form.username,
-                        password: form.password,
-                        totp_enabled: false,
-                        totp_secret: None,
-                    })
-                }) {
-                    Ok(_) => Ok(res),
-                    Err(_) => {
-                        let mut res: tide::Response = Redirect::new("/register").into();
-                        res.flash_error("invalid credentials");
-                        Ok(res)
-                    }
-                }
-            }
-            Err(e) => {
-                let mut res: tide::Response = Redirect::new("/register").into();
-                res.flash_error(serde_json::json!(e.field_errors()).to_string());
-                Ok(res)
-            }
-        },
+    let form = match req.body_form::<UserCreateForm>().await {
+        Ok(form) => form,
         Err(e) => {
             let mut res: tide::Response = Redirect::new("/register").into();
             res.flash_error(e.to_string());
+            return Ok(res);
+        }
+    };
+    if let Err(e) = form.validate() {
+        req.set_form_state(FormState::new(&form).with_errors(&e))?;
+        return Ok(Redirect::new("/register").into());
+    }
+
+    let user = User::new(form.username.clone(), &form.password)?;
+    match req.state().users().insert(user).await {
+        Ok(user) => {
+            if let Err(e) = send_verification(req.state(), &user).await {
+                tide::log::error!("failed to send verification email: {}", e);
+            }
+            Ok(Redirect::new("/").into())
+        }
+        Err(_) => {
+            req.set_form_state(FormState::new(&form))?;
+            let mut res: tide::Response = Redirect::new("/register").into();
+            res.flash_error("invalid credentials");
             Ok(res)
         }
     }
 }
 
+/// Where to go once the password has been accepted. The page the user came
+/// for is kept until the second factor has been entered as well.
+pub fn after_login(req: &mut Request<State>) -> String {
+    if req.requires_totp() {
+        String::from("/")
+    } else {
+        req.take_return_to()
+    }
+}
+
 pub async fn authenticate(mut req: Request<State>) -> tide::Result {
     match req.body_form::<UserForm>().await {
-        Ok(form) => USERS.with_borrow_mut(|db| {
-            if let Ok(user) = db.authenticate(form.username, form.password) {
-                let claims = Claims {
-                    username: user.username.clone(),
-                    exp: 10000000000,
-                    sub: user.username,
-                    uid: user._id,
-                    totp_enabled: user.totp_enabled,
-                    totp_attempt: 0,
-                    totp: None,
-                };
-                req.login(claims)?;
-                Ok(Redirect::new("/").into())
-            } else {
-                let mut res: tide::Response = Redirect::new("/").into();
-                res.flash_error("invalid credentials");
-                Ok(res)
+        Ok(form) => {
+            let users = req.state().users();
+            match users.authenticate(&form.username, &form.password).await {
+                Ok(user) => {
+                    let claims = Claims::new(&user, req.state().config.session_lifetime());
+                    req.start_session(claims).await?;
+                    Ok(Redirect::new(after_login(&mut req)).into())
+                }
+                Err(e) => {
+                    audit_failed_login(&req, &form.username, &e).await;
+                    let mut res: tide::Response = Redirect::new("/").into();
+                    if e.kind() == ErrorKind::PermissionDenied {
+                        res.flash_error("this account has been suspended");
+                    } else {
+                        res.flash_error("invalid credentials");
+                    }
+                    Ok(res)
+                }
             }
-        }),
+        }
         Err(e) => {
             let mut res: tide::Response = Redirect::new("/").into();
             res.flash_error(e.to_string());
@@ -86,33 +96,34 @@ pub async fn authenticate(mut req: Request<State>) -> tide::Result {
 }
 
 pub async fn authenticate_otp(mut req: Request<State>) -> tide::Result {
-    if !req.is_authenticated() || !req.requires_totp() {
+    if !req.is_authenticated().await || !req.requires_totp() {
         return Ok(Redirect::new("/").into());
     }
 
     match req.body_form::<ValidateForm>().await {
         Ok(form) => {
-            let key_ascii = req.user().unwrap().totp_secret.clone().unwrap();
-            let valid = libreauth::oath::TOTPBuilder::new()
-                .ascii_key(&key_ascii)
-                .finalize()
-                .unwrap()
-                .is_valid(&form.code);
-            if valid {
-                let mut claims = req.claims().unwrap();
-                claims.totp_attempt = claims.totp_attempt + 1;
-                claims.totp = Some(10000000000);
-                req.login(claims)?;
-                Ok(Redirect::new("/").into())
-            } else {
-                let mut claims = req.claims().unwrap();
-                claims.totp_attempt = claims.totp_attempt + 1;
-                claims.totp = None;
-                req.login(claims)?;
+            let user = req.user().ok_or(AppError::Unauthorized)?.clone();
+            let mut claims = req.claims().ok_or(AppError::Unauthorized)?;
+            claims.totp_attempt += 1;
+            match verify_second_factor(&req, &user, &form.code, true).await? {
+                SecondFactor::Accepted => {
+                    claims.totp = Some(claims.exp);
+                    req.login(claims)?;
+                    Ok(Redirect::new(req.take_return_to()).into())
+                }
+                SecondFactor::Rejected => {
+                    claims.totp = None;
+                    req.login(claims)?;
 
-                let mut res: tide::Response = Redirect::new("/").into();
-                res.flash_error("invalid otp");
-                Ok(res)
+                    let mut res: tide::Response = Redirect::new("/").into();
+                    res.flash_error("invalid otp");
+                    Ok(res)
+                }
+                SecondFactor::LockedOut => {
+                    let mut res: tide::Response = Redirect::new("/").into();
+                    res.flash_error("too many failed attempts, try again later");
+                    Ok(res)
+                }
             }
         }
         Err(e) => {
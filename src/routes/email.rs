@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use serde_json::json;
+use tide::{Redirect, Request, Response, Server};
+use validator::Validate;
+
+use super::{ForgotPasswordForm, ResetPasswordForm};
+use crate::forms::FormState;
+use crate::jwt::{EmailToken, Purpose};
+use crate::mailer::Email;
+use crate::prelude::*;
+use crate::repos::audit::AuditKind;
+use crate::repos::token::{ConsumedTokenRepository, RefreshTokenRepository};
+use crate::repos::user::{User, UserRepository};
+use crate::templates::TemplateResponse;
+use crate::State;
+
+pub const VERIFY_EMAIL_TTL: Duration = Duration::from_secs(60 * 60 * 48);
+pub const RESET_PASSWORD_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub fn configure(app: &mut Server<State>) {
+    app.at("/verify-email/:token").get(verify_email);
+    app.at("/forgot-password")
+        .rate_limited()
+        .get(forgot_password)
+        .post(forgot_password_post);
+    app.at("/reset-password/:token")
+        .get(reset_password)
+        .post(reset_password_post);
+}
+
+pub async fn send_verification(state: &State, user: &User) -> tide::Result<()> {
+    let token = EmailToken::new(&user._id, Purpose::VerifyEmail, VERIFY_EMAIL_TTL);
+    let link = format!(
+        "{}/verify-email/{}",
+        state.config.app_url,
+        state.keys.sign(&token)?
+    );
+    let body = state.render(
+        "verify-email.txt",
+        &json!({ "username": user.username, "link": link }),
+    )?;
+    state
+        .mailer
+        .send(Email {
+            to: user.username.clone(),
+            subject: String::from("Verify your email address"),
+            body,
+        })
+        .await
+}
+
+pub async fn send_password_reset(state: &State, user: &User) -> tide::Result<()> {
+    let token = EmailToken::new(&user._id, Purpose::ResetPassword, RESET_PASSWORD_TTL);
+    let link = format!(
+        "{}/reset-password/{}",
+        state.config.app_url,
+        state.keys.sign(&token)?
+    );
+    let body = state.render(
+        "reset-password.txt",
+        &json!({ "username": user.username, "link": link }),
+    )?;
+    state
+        .mailer
+        .send(Email {
+            to: user.username.clone(),
+            subject: String::from("Reset your password"),
+            body,
+        })
+        .await
+}
+
+/// Checks that `token` is a valid, unused token for `purpose` and returns
+/// it along with the user it was issued to.
+pub async fn check_token(
+    state: &State,
+    token: &str,
+    purpose: Purpose,
+) -> tide::Result<Option<(EmailToken, User)>> {
+    let token = match state.keys.verify::<EmailToken>(token) {
+        Ok(token) if token.purpose == purpose => token,
+        _ => return Ok(None),
+    };
+    if state.consumed_tokens().is_consumed(&token.jti).await? {
+        return Ok(None);
+    }
+    match state.users().get_by_id(&token.sub).await {
+        Ok(user) => Ok(Some((token, user))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Like [`check_token`], but also marks the token as used.
+pub async fn redeem_token(
+    state: &State,
+    token: &str,
+    purpose: Purpose,
+) -> tide::Result<Option<User>> {
+    match check_token(state, token, purpose).await? {
+        Some((token, user))
+            if state
+                .consumed_tokens()
+                .consume(&token.jti, token.exp)
+                .await? =>
+        {
+            Ok(Some(user))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub async fn verify_email(req: Request<State>) -> tide::Result {
+    let token = req.param("token")?;
+    let mut res: Response = Redirect::new("/").into();
+    match redeem_token(req.state(), token, Purpose::VerifyEmail).await? {
+        Some(mut user) => {
+            user.email_verified = true;
+            req.state().users().update(user).await?;
+            res.flash_info("email address verified");
+        }
+        None => res.flash_error("verification link is invalid or has expired"),
+    }
+    Ok(res)
+}
+
+pub async fn resend_verification(req: Request<State>) -> tide::Result {
+    let user = req.user().unwrap().clone();
+    let mut res: Response = Redirect::new("/account/settings").into();
+    if user.email_verified {
+        res.flash_info("email address is already verified");
+    } else {
+        send_verification(req.state(), &user).await?;
+        res.flash_info("verification email sent");
+    }
+    Ok(res)
+}
+
+pub async fn forgot_password(req: Request<State>) -> tide::Result {
+    TemplateResponse::new(req, "forgot-password.html").into()
+}
+
+pub async fn forgot_password_post(mut req: Request<State>) -> tide::Result {
+    let mut res: Response = Redirect::new("/forgot-password").into();
+    match req.body_form::<ForgotPasswordForm>().await {
+        Ok(form) => {
+            // the response is the same whether or not the account exists
+            if let Ok(user) = req.state().users().get_by_username(&form.username).await {
+                send_password_reset(req.state(), &user).await?;
+            }
+            res.flash_info("if an account exists for that address, a reset link has been sent");
+        }
+        Err(e) => res.flash_error(e.to_string()),
+    }
+    Ok(res)
+}
+
+pub async fn reset_password(req: Request<State>) -> tide::Result {
+    let token = req.param("token")?.to_string();
+    match check_token(req.state(), &token, Purpose::ResetPassword).await? {
+        Some(_) => TemplateResponse::new(req, "reset-password.html")
+            .with_data(json!({ "token": token }))
+            .into(),
+        None => {
+            let mut res: Response = Redirect::new("/forgot-password").into();
+            res.flash_error("reset link is invalid or has expired");
+            Ok(res)
+        }
+    }
+}
+
+pub async fn reset_password_post(mut req: Request<State>) -> tide::Result {
+    let token = req.param("token")?.to_string();
+    let retry = format!("/reset-password/{}", token);
+    let form = match req.body_form::<ResetPasswordForm>().await {
+        Ok(form) => form,
+        Err(e) => {
+            let mut res: Response = Redirect::new(&retry).into();
+            res.flash_error(e.to_string());
+            return Ok(res);
+        }
+    };
+    if let Err(e) = form.validate() {
+        req.set_form_state(FormState::new(&form).with_errors(&e))?;
+        return Ok(Redirect::new(&retry).into());
+    }
+
+    match redeem_token(req.state(), &token, Purpose::ResetPassword).await? {
+        Some(user) => {
+            update_password(&req, user, form.password).await?;
+            let mut res: Response = Redirect::new("/").into();
+            res.flash_info("password updated, log in with your new password");
+            Ok(res)
+        }
+        None => {
+            let mut res: Response = Redirect::new("/forgot-password").into();
+            res.flash_error("reset link is invalid or has expired");
+            Ok(res)
+        }
+    }
+}
+
+/// Sets a new password and signs out every session and refresh token issued
+/// with the old one.
+pub async fn update_password(
+    req: &Request<State>,
+    mut user: User,
+    password: String,
+) -> tide::Result<User> {
+    let state = req.state();
+    user.set_password(&password)?;
+    // following the reset link proves ownership of the address
+    user.email_verified = true;
+    let user = state.users().update(user).await?;
+    state.refresh_tokens().revoke_all(&user._id).await?;
+    state.destroy_sessions(&user._id, None).await?;
+    req.audit(AuditKind::PasswordChanged, &user._id, None)
+        .await?;
+    Ok(user)
+}
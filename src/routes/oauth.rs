@@ -0,0 +1,415 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+use tide::{http::Url, Redirect, Request, Response, Server, StatusCode};
+use uuid::Uuid;
+use validator::Validate;
+
+use super::OAuthAppForm;
+use crate::forms::FormState;
+use crate::json::JsonResponse;
+use crate::oidc::code_challenge;
+use crate::policy;
+use crate::prelude::*;
+use crate::repos::oauth::{
+    AuthorizationCode, AuthorizationCodeRepository, Grant, GrantRepository, OAuthApp,
+    OAuthAppRepository,
+};
+use crate::repos::token::{hash_token, AccessToken, AccessTokenRepository, Scope};
+use crate::templates::TemplateResponse;
+use crate::{timestamp, State};
+
+pub const AUTHORIZATION_CODE_TTL: Duration = Duration::from_secs(60 * 10);
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// Parameters of an authorization request, sent as the query of the
+/// authorize page and posted back from the consent form.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthorizeParams {
+    response_type: Option<String>,
+    client_id: String,
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    decision: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenForm {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    client_secret: Option<String>,
+    code_verifier: String,
+}
+
+pub fn configure(app: &mut Server<State>) {
+    app.at("/oauth/authorize")
+        .authenticated()
+        .get(authorize)
+        .post(authorize_post);
+    app.at("/oauth/token").post(token);
+}
+
+fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Sends the browser back to the app with `params` added to the redirect uri.
+fn redirect_back(
+    redirect_uri: &str,
+    params: &[(&str, &str)],
+    state: &Option<String>,
+) -> tide::Result {
+    let mut url = Url::parse(redirect_uri)?;
+    {
+        let mut query = url.query_pairs_mut();
+        for (key, value) in params {
+            query.append_pair(key, value);
+        }
+        if let Some(state) = state {
+            query.append_pair("state", state);
+        }
+    }
+    Ok(Redirect::new(url).into())
+}
+
+/// Why an authorization request was turned down.
+enum Rejection {
+    /// The request can't be tied to a registered redirect uri, so the error
+    /// is shown here instead of being sent back to the app.
+    UnknownClient,
+    Redirect(&'static str, &'static str),
+}
+
+async fn check_request(
+    req: &Request<State>,
+    params: &AuthorizeParams,
+) -> tide::Result<Result<(OAuthApp, Vec<Scope>), Rejection>> {
+    let app = match req.state().oauth_apps().get(&params.client_id).await {
+        Ok(app) if app.redirect_uris.contains(&params.redirect_uri) => app,
+        _ => return Ok(Err(Rejection::UnknownClient)),
+    };
+
+    if params.response_type.as_deref() != Some("code") {
+        return Ok(Err(Rejection::Redirect(
+            "unsupported_response_type",
+            "only the code response type is supported",
+        )));
+    } else if params.code_challenge.is_none()
+        || params.code_challenge_method.as_deref() != Some("S256")
+    {
+        return Ok(Err(Rejection::Redirect(
+            "invalid_request",
+            "a S256 code challenge is required",
+        )));
+    }
+
+    match Scope::parse_list(&params.scope) {
+        Some(scopes) if !scopes.is_empty() => Ok(Ok((app, scopes))),
+        _ => Ok(Err(Rejection::Redirect(
+            "invalid_scope",
+            "unknown or missing scope",
+        ))),
+    }
+}
+
+fn reject(req: Request<State>, params: &AuthorizeParams, rejection: Rejection) -> tide::Result {
+    match rejection {
+        Rejection::UnknownClient => TemplateResponse::new(req, "consent.html")
+            .with_data(json!({ "error": "unknown app or redirect uri" }))
+            .with_status(StatusCode::BadRequest)
+            .into(),
+        Rejection::Redirect(error, description) => redirect_back(
+            &params.redirect_uri,
+            &[("error", error), ("error_description", description)],
+            &params.state,
+        ),
+    }
+}
+
+pub async fn authorize(req: Request<State>) -> tide::Result {
+    let params: AuthorizeParams = req.query()?;
+    let (app, scopes) = match check_request(&req, &params).await? {
+        Ok(checked) => checked,
+        Err(rejection) => return reject(req, &params, rejection),
+    };
+
+    TemplateResponse::new(req, "consent.html")
+        .with_data(json!({
+            "app": { "name": app.name, "client_id": app._id },
+            "scopes": scopes,
+            "scope": Scope::join(&scopes),
+            "redirect_uri": params.redirect_uri,
+            "state": params.state,
+            "code_challenge": params.code_challenge,
+        }))
+        .into()
+}
+
+pub async fn authorize_post(mut req: Request<State>) -> tide::Result {
+    let mut params: AuthorizeParams = req.body_form().await?;
+    // the consent form only posts back what was already checked
+    params.response_type = Some(String::from("code"));
+    params.code_challenge_method = Some(String::from("S256"));
+    params.state = params.state.filter(|state| !state.is_empty());
+    let (app, scopes) = match check_request(&req, &params).await? {
+        Ok(checked) => checked,
+        Err(rejection) => return reject(req, &params, rejection),
+    };
+
+    if params.decision.as_deref() != Some("allow") {
+        return redirect_back(
+            &params.redirect_uri,
+            &[("error", "access_denied")],
+            &params.state,
+        );
+    }
+
+    let uid = req.user().unwrap()._id.clone();
+    let code = random_token();
+    req.state()
+        .authorization_codes()
+        .insert(AuthorizationCode {
+            _id: hash_token(&code),
+            client_id: app._id.clone(),
+            uid: uid.clone(),
+            redirect_uri: params.redirect_uri.clone(),
+            scopes: scopes.clone(),
+            code_challenge: params.code_challenge.clone().unwrap_or_default(),
+            expires_at: timestamp() + AUTHORIZATION_CODE_TTL.as_secs() as usize,
+        })
+        .await?;
+    req.state()
+        .grants()
+        .upsert(Grant {
+            _id: Uuid::new_v4().to_string(),
+            uid,
+            client_id: app._id,
+            scopes,
+            created_at: timestamp(),
+        })
+        .await?;
+
+    redirect_back(&params.redirect_uri, &[("code", &code)], &params.state)
+}
+
+fn token_error(code: StatusCode, error: &str, description: &str) -> tide::Result {
+    JsonResponse::new(json!({ "error": error, "error_description": description }))
+        .with_status(code)
+        .into()
+}
+
+/// Exchanges an authorization code for an access token. Tokens are stored
+/// alongside personal access tokens and go through the same bearer
+/// middleware, limited to the granted scopes.
+pub async fn token(mut req: Request<State>) -> tide::Result {
+    let form: TokenForm = match req.body_form().await {
+        Ok(form) => form,
+        Err(e) => return token_error(StatusCode::BadRequest, "invalid_request", &e.to_string()),
+    };
+    if form.grant_type != "authorization_code" {
+        return token_error(
+            StatusCode::BadRequest,
+            "unsupported_grant_type",
+            "only authorization_code is supported",
+        );
+    }
+
+    let app = match req.state().oauth_apps().get(&form.client_id).await {
+        Ok(app) => app,
+        Err(_) => return token_error(StatusCode::Unauthorized, "invalid_client", "unknown client"),
+    };
+    if let Some(secret_hash) = &app.secret_hash {
+        let secret = form.client_secret.as_deref().map(hash_token);
+        if secret.as_ref() != Some(secret_hash) {
+            return token_error(
+                StatusCode::Unauthorized,
+                "invalid_client",
+                "invalid client secret",
+            );
+        }
+    }
+
+    let code = match req
+        .state()
+        .authorization_codes()
+        .take(&hash_token(&form.code))
+        .await
+    {
+        Ok(code) => code,
+        Err(_) => {
+            return token_error(
+                StatusCode::BadRequest,
+                "invalid_grant",
+                "code is invalid or has expired",
+            )
+        }
+    };
+    if code.client_id != app._id
+        || code.redirect_uri != form.redirect_uri
+        || code.code_challenge != code_challenge(&form.code_verifier)
+    {
+        return token_error(
+            StatusCode::BadRequest,
+            "invalid_grant",
+            "code does not match this request",
+        );
+    }
+
+    let token = format!("{}{}", AccessToken::OAUTH_PREFIX, random_token());
+    let scope = Scope::join(&code.scopes);
+    req.state()
+        .access_tokens()
+        .insert(AccessToken {
+            _id: Uuid::new_v4().to_string(),
+            uid: code.uid,
+            name: app.name,
+            hash: hash_token(&token),
+            scopes: code.scopes,
+            created_at: timestamp(),
+            expires_at: Some(timestamp() + ACCESS_TOKEN_TTL.as_secs() as usize),
+            last_used_at: None,
+            revoked: false,
+            client_id: Some(app._id),
+        })
+        .await?;
+
+    JsonResponse::new(json!({
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": ACCESS_TOKEN_TTL.as_secs(),
+        "scope": scope,
+    }))
+    .into()
+}
+
+/// Apps the current user has authorized, with the app name resolved.
+pub async fn authorized_apps(req: &Request<State>) -> tide::Result<Vec<serde_json::Value>> {
+    let uid = &req.user().unwrap()._id;
+    let mut authorized = Vec::new();
+    for grant in req.state().grants().list(uid).await? {
+        if let Ok(app) = req.state().oauth_apps().get(&grant.client_id).await {
+            authorized.push(json!({
+                "client_id": app._id,
+                "name": app.name,
+                "scopes": grant.scopes,
+                "created_at": grant.created_at,
+            }));
+        }
+    }
+    Ok(authorized)
+}
+
+/// Removes the user's grant to `client_id` and revokes every token the app
+/// holds for them.
+pub async fn revoke_grant(state: &State, uid: &str, client_id: &str) -> tide::Result<bool> {
+    let removed = state.grants().remove(uid, client_id).await.is_ok();
+    state.access_tokens().revoke_client(uid, client_id).await?;
+    Ok(removed)
+}
+
+pub async fn apps(req: Request<State>) -> tide::Result {
+    render_apps(req, None).await
+}
+
+async fn render_apps(req: Request<State>, new_app: Option<serde_json::Value>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let apps = req.state().oauth_apps().list(&uid).await?;
+    let authorized = authorized_apps(&req).await?;
+    TemplateResponse::new(req, "apps.html")
+        .with_data(json!({
+            "apps": apps,
+            "authorized": authorized,
+            "new_app": new_app,
+        }))
+        .into()
+}
+
+pub async fn create_app(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_form::<OAuthAppForm>().await {
+        Ok(form) => form,
+        Err(e) => {
+            let mut res: Response = Redirect::new("/account/apps").into();
+            res.flash_error(e.to_string());
+            return Ok(res);
+        }
+    };
+    if let Err(e) = form.validate() {
+        req.set_form_state(FormState::new(&form).with_errors(&e))?;
+        return Ok(Redirect::new("/account/apps").into());
+    }
+
+    let redirect_uris: Vec<String> = form
+        .redirect_uris
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    let valid = !redirect_uris.is_empty()
+        && redirect_uris
+            .iter()
+            .all(|uri| Url::parse(uri).is_ok_and(|url| url.fragment().is_none()));
+    if !valid {
+        req.set_form_state(FormState::new(&form))?;
+        let mut res: Response = Redirect::new("/account/apps").into();
+        res.flash_error("redirect uris must be absolute urls without a fragment");
+        return Ok(res);
+    }
+
+    let secret = form.confidential.is_some().then(random_token);
+    let app = req
+        .state()
+        .oauth_apps()
+        .insert(OAuthApp {
+            _id: Uuid::new_v4().simple().to_string(),
+            owner: req.user().unwrap()._id.clone(),
+            name: form.name,
+            redirect_uris,
+            secret_hash: secret.as_deref().map(hash_token),
+            created_at: timestamp(),
+        })
+        .await?;
+
+    // the secret is only ever rendered here, it is not recoverable afterwards
+    render_apps(
+        req,
+        Some(json!({ "client_id": app._id, "client_secret": secret })),
+    )
+    .await
+}
+
+pub async fn delete_app(req: Request<State>) -> tide::Result {
+    let user = req.user().unwrap();
+    let mut res: Response = Redirect::new("/account/apps").into();
+    let app = match req.state().oauth_apps().get(req.param("id")?).await {
+        Ok(app) if policy::can_manage(user, &app) => app,
+        _ => {
+            res.flash_error("app not found");
+            return Ok(res);
+        }
+    };
+    req.state()
+        .oauth_apps()
+        .remove(&app.owner, &app._id)
+        .await?;
+    req.state().grants().remove_client(&app._id).await?;
+    req.state().access_tokens().revoke_app(&app._id).await?;
+    res.flash_info("app deleted");
+    Ok(res)
+}
+
+pub async fn revoke_app(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let mut res: Response = Redirect::new("/account/apps").into();
+    if revoke_grant(req.state(), &uid, req.param("id")?).await? {
+        res.flash_info("access revoked");
+    } else {
+        res.flash_error("app not found");
+    }
+    Ok(res)
+}
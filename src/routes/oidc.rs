@@ -0,0 +1,229 @@
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Redirect, Request, Response, Server};
+use uuid::Uuid;
+
+use crate::oidc::{Authorization, IdToken, Provider};
+use crate::prelude::*;
+use crate::repos::identity::{Identity, IdentityRepository};
+use crate::repos::user::{User, UserRepository};
+use crate::templates::TemplateResponse;
+use crate::{timestamp, Claims, State};
+
+const SESSION_KEY: &str = "tide.oidc";
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+pub fn configure(app: &mut Server<State>) {
+    app.at("/auth/oidc/:provider").get(login);
+    app.at("/auth/oidc/:provider/callback").get(callback);
+}
+
+fn provider(req: &Request<State>) -> Option<Provider> {
+    let name = req.param("provider").ok()?;
+    req.state().oidc.provider(name).cloned()
+}
+
+fn failed(target: &str, message: &str) -> tide::Result {
+    let mut res: Response = Redirect::new(target).into();
+    res.flash_error(message);
+    Ok(res)
+}
+
+async fn start(mut req: Request<State>, link_uid: Option<String>) -> tide::Result {
+    let target = if link_uid.is_some() {
+        "/account/identities"
+    } else {
+        "/"
+    };
+    let provider = match provider(&req) {
+        Some(provider) => provider,
+        None => return failed(target, "unknown sign in provider"),
+    };
+
+    match req.state().oidc.authorize(&provider, link_uid).await {
+        Ok((url, authorization)) => {
+            req.session_mut().insert(SESSION_KEY, authorization)?;
+            Ok(Redirect::new(url).into())
+        }
+        Err(e) => {
+            tide::log::error!("oidc discovery for {} failed: {}", provider.name, e);
+            failed(target, &format!("{} is unavailable", provider.display_name))
+        }
+    }
+}
+
+pub async fn login(req: Request<State>) -> tide::Result {
+    start(req, None).await
+}
+
+pub async fn link(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    start(req, Some(uid)).await
+}
+
+pub async fn callback(mut req: Request<State>) -> tide::Result {
+    let authorization = req.session().get::<Authorization>(SESSION_KEY);
+    req.session_mut().remove(SESSION_KEY);
+    let authorization = match authorization {
+        Some(authorization) => authorization,
+        None => return failed("/", "sign in expired, try again"),
+    };
+    let target = if authorization.link_uid.is_some() {
+        "/account/identities"
+    } else {
+        "/"
+    };
+
+    let query: CallbackQuery = req.query()?;
+    let provider = match provider(&req) {
+        Some(provider) if provider.name == authorization.provider => provider,
+        _ => return failed(target, "unknown sign in provider"),
+    };
+    if query.state.as_deref() != Some(authorization.state.as_str()) {
+        return failed(target, "sign in expired, try again");
+    }
+    let code = match (query.code, query.error) {
+        (Some(code), None) => code,
+        _ => {
+            return failed(
+                target,
+                &format!("{} sign in was cancelled", provider.display_name),
+            )
+        }
+    };
+
+    let id_token = match req
+        .state()
+        .oidc
+        .exchange(&provider, &authorization, &code)
+        .await
+    {
+        Ok(id_token) => id_token,
+        Err(e) => {
+            tide::log::error!("oidc sign in with {} failed: {}", provider.name, e);
+            return failed(target, &format!("{} sign in failed", provider.display_name));
+        }
+    };
+
+    match authorization.link_uid {
+        Some(uid) => link_identity(req, &provider, id_token, uid).await,
+        None => sign_in(req, &provider, id_token).await,
+    }
+}
+
+fn identity(provider: &Provider, id_token: &IdToken, uid: &str) -> Identity {
+    Identity {
+        _id: Uuid::new_v4().to_string(),
+        uid: uid.to_string(),
+        provider: provider.name.clone(),
+        subject: id_token.sub.clone(),
+        email: id_token.email.clone(),
+        created_at: timestamp(),
+    }
+}
+
+async fn link_identity(
+    req: Request<State>,
+    provider: &Provider,
+    id_token: IdToken,
+    uid: String,
+) -> tide::Result {
+    let mut res: Response = Redirect::new("/account/identities").into();
+    let identities = req.state().identities();
+    match identities.get(&provider.name, &id_token.sub).await {
+        Ok(existing) if existing.uid == uid => res.flash_info("account is already linked"),
+        Ok(_) => res.flash_error("that account is linked to another user"),
+        Err(_) => {
+            identities
+                .insert(identity(provider, &id_token, &uid))
+                .await?;
+            res.flash_info(format!("linked your {} account", provider.display_name));
+        }
+    }
+    Ok(res)
+}
+
+/// Logs in the user linked to the identity, linking it first to the account
+/// with the same verified email, or creating an account for it. Identities
+/// without an email the provider has verified are refused, otherwise anyone
+/// could claim an address before its owner signs up.
+async fn sign_in(mut req: Request<State>, provider: &Provider, id_token: IdToken) -> tide::Result {
+    let state = req.state().clone();
+    let user = match state.identities().get(&provider.name, &id_token.sub).await {
+        Ok(identity) => state.users().get_by_id(&identity.uid).await?,
+        Err(_) => {
+            let email = match id_token.verified_email() {
+                Some(email) => email,
+                None => {
+                    let message = "the provider did not share a verified email address";
+                    return failed("/", message);
+                }
+            };
+            let user = match state.users().get_by_username(&email).await {
+                Ok(user) if user.email_verified => user,
+                Ok(_) => {
+                    let message = "an account with that email exists, log in to link it";
+                    return failed("/", message);
+                }
+                Err(_) => {
+                    // the account can only be logged into through the provider
+                    // until a password is set with a reset link
+                    let mut user = User::new(email, &Uuid::new_v4().simple().to_string())?;
+                    user.email_verified = true;
+                    state.users().insert(user).await?
+                }
+            };
+            state
+                .identities()
+                .insert(identity(provider, &id_token, &user._id))
+                .await?;
+            user
+        }
+    };
+
+    if user.is_suspended() {
+        return failed("/", "this account has been suspended");
+    }
+    let claims = Claims::new(&user, req.state().config.session_lifetime());
+    req.start_session(claims).await?;
+    Ok(Redirect::new(super::auth::after_login(&mut req)).into())
+}
+
+pub async fn identities(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let identities = req.state().identities().list(&uid).await?;
+    let providers: Vec<_> = req
+        .state()
+        .oidc
+        .providers()
+        .iter()
+        .map(|provider| {
+            let linked = identities.iter().any(|i| i.provider == provider.name);
+            json!({ "provider": provider, "linked": linked })
+        })
+        .collect();
+    TemplateResponse::new(req, "identities.html")
+        .with_data(json!({ "identities": identities, "providers": providers }))
+        .into()
+}
+
+pub async fn unlink(req: Request<State>) -> tide::Result {
+    let uid = req.user().unwrap()._id.clone();
+    let mut res: Response = Redirect::new("/account/identities").into();
+    match req
+        .state()
+        .identities()
+        .remove(&uid, req.param("id")?)
+        .await
+    {
+        Ok(_) => res.flash_info("account unlinked"),
+        Err(_) => res.flash_error("linked account not found"),
+    }
+    Ok(res)
+}
@@ -0,0 +1,373 @@
+use std::io::{Error, ErrorKind};
+
+use serde::Deserialize;
+use serde_json::json;
+use tide::{http::Url, Redirect, Request, Response, Server};
+use validator::Validate;
+
+use super::admin::suspend_user;
+use super::{ReportForm, ResolveForm};
+use crate::forms::FormState;
+use crate::mailer::Email;
+use crate::policy;
+use crate::prelude::*;
+use crate::repos::audit::AuditKind;
+use crate::repos::post::{Post, PostRepository};
+use crate::repos::report::{
+    Outcome, Report, ReportRepository, ReportStatus, ReportTarget, Reporter, TargetKind,
+};
+use crate::repos::user::{User, UserRepository};
+use crate::templates::TemplateResponse;
+use crate::{timestamp, State};
+
+const PAGE_SIZE: i64 = 50;
+
+#[derive(Deserialize)]
+struct QueueQuery {
+    status: Option<ReportStatus>,
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    target_type: TargetKind,
+    target_id: String,
+}
+
+/// Routes of the moderation queue, nested under `/admin`.
+pub fn configure(app: &mut Server<State>) {
+    app.at("/reports").get(queue);
+    app.at("/reports/:id").get(report);
+    app.at("/reports/:id/claim").post(claim);
+    app.at("/reports/:id/resolve").post(resolve);
+}
+
+fn report_page(id: &str) -> String {
+    format!("/admin/reports/{}", id)
+}
+
+fn back(target: &str, message: &str) -> tide::Result {
+    let mut res: Response = Redirect::new(target).into();
+    res.flash_error(message);
+    Ok(res)
+}
+
+/// The account a report is about, which is the one warned or suspended. For
+/// a post that's its author.
+async fn reported_account(state: &State, report: &Report) -> tide::Result<User> {
+    let uid = match report.target.kind {
+        TargetKind::Account => report.target.id.clone(),
+        TargetKind::Post => state.posts().get(&report.target.id).await?.uid,
+    };
+    Ok(state.users().get_by_id(&uid).await?)
+}
+
+/// The outcomes a moderator may pick for a report. Only posts can be removed.
+fn outcomes(report: &Report) -> Vec<Outcome> {
+    let mut outcomes = vec![Outcome::Dismissed, Outcome::Warned, Outcome::Suspended];
+    if report.target.kind == TargetKind::Post {
+        outcomes.push(Outcome::RemovedContent);
+    }
+    outcomes
+}
+
+/// Files a report by `uid` after checking its target exists and isn't their
+/// own. Fails with `InvalidInput` for their own account or post, `NotFound`
+/// for a missing target and `AlreadyExists` if they reported it before.
+pub async fn file_report(state: &State, uid: &str, form: ReportForm) -> Result<Report, Error> {
+    match form.target_type {
+        TargetKind::Account if form.target_id == uid => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "you can't report yourself",
+            ));
+        }
+        TargetKind::Account => {
+            if state.users().get_by_id(&form.target_id).await.is_err() {
+                return Err(Error::new(ErrorKind::NotFound, "account not found"));
+            }
+        }
+        TargetKind::Post => match state.posts().get(&form.target_id).await {
+            Ok(post) if post.uid == uid => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "you can't report your own post",
+                ));
+            }
+            Ok(_) => {}
+            Err(_) => return Err(Error::new(ErrorKind::NotFound, "post not found")),
+        },
+    }
+
+    let target = ReportTarget {
+        kind: form.target_type,
+        id: form.target_id,
+    };
+    let reporter = Reporter {
+        uid: uid.to_string(),
+        reason: form.reason,
+        comment: form.comment.filter(|c| !c.trim().is_empty()),
+        created_at: timestamp(),
+    };
+    state.reports().file(target, reporter).await
+}
+
+fn new_report_page(kind: TargetKind, id: &str) -> String {
+    let kind = match kind {
+        TargetKind::Account => "account",
+        TargetKind::Post => "post",
+    };
+    let mut url = Url::parse("http://localhost/account/report").unwrap();
+    url.query_pairs_mut()
+        .append_pair("target_type", kind)
+        .append_pair("target_id", id);
+    format!("{}?{}", url.path(), url.query().unwrap_or_default())
+}
+
+/// The form for reporting an account or post, linked to as
+/// `/account/report?target_type=post&target_id=<id>`.
+pub async fn new_report(req: Request<State>) -> tide::Result {
+    let query = match req.query::<ReportQuery>() {
+        Ok(query) => query,
+        Err(_) => return back("/", "there is nothing to report"),
+    };
+    TemplateResponse::new(req, "report.html")
+        .with_data(json!({ "target_type": query.target_type, "target_id": query.target_id }))
+        .into()
+}
+
+pub async fn file(mut req: Request<State>) -> tide::Result {
+    let form = match req.body_form::<ReportForm>().await {
+        Ok(form) => form,
+        Err(e) => return back("/", &e.to_string()),
+    };
+    let page = new_report_page(form.target_type, &form.target_id);
+    if let Err(e) = form.validate() {
+        req.set_form_state(FormState::new(&form).with_errors(&e))?;
+        return Ok(Redirect::new(page).into());
+    }
+
+    let uid = req.user().unwrap()._id.clone();
+    match file_report(req.state(), &uid, form).await {
+        Ok(_) => {
+            let mut res: Response = Redirect::new("/").into();
+            res.flash_info("thanks, a moderator will look at your report");
+            Ok(res)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            back(&page, "you have already reported this")
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::NotFound) => {
+            back(&page, &e.to_string())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn queue(req: Request<State>) -> tide::Result {
+    let status = req
+        .query::<QueueQuery>()?
+        .status
+        .unwrap_or(ReportStatus::Open);
+    let reports = req.state().reports().list(status, PAGE_SIZE).await?;
+    TemplateResponse::new(req, "admin/reports.html")
+        .with_data(json!({ "status": status, "reports": reports }))
+        .into()
+}
+
+pub async fn report(req: Request<State>) -> tide::Result {
+    let report = match req.state().reports().get(req.param("id")?).await {
+        Ok(report) => report,
+        Err(_) => return back("/admin/reports", "report not found"),
+    };
+    let target = reported_account(req.state(), &report).await.ok();
+    let post: Option<Post> = match report.target.kind {
+        TargetKind::Post => req.state().posts().get(&report.target.id).await.ok(),
+        TargetKind::Account => None,
+    };
+    let moderator = req.user().unwrap();
+    let claimed_by_me = report.moderator.as_deref() == Some(moderator._id.as_str());
+    let can_sanction = target
+        .as_ref()
+        .is_some_and(|target| policy::can_sanction(moderator, target));
+    let outcomes: Vec<Outcome> = outcomes(&report)
+        .into_iter()
+        .filter(|outcome| *outcome == Outcome::Dismissed || can_sanction)
+        .collect();
+    TemplateResponse::new(req, "admin/report.html")
+        .with_data(json!({
+            "report": report,
+            "target": target.map(|t| json!({ "id": t._id, "username": t.username, "role": t.role })),
+            "post": post.map(|p| json!({ "body": p.body, "created_at": p.created_at })),
+            "claimed_by_me": claimed_by_me,
+            "outcomes": outcomes,
+        }))
+        .into()
+}
+
+pub async fn claim(req: Request<State>) -> tide::Result {
+    let id = req.param("id")?;
+    let uid = &req.user().unwrap()._id;
+    let mut res: Response = Redirect::new(report_page(id)).into();
+    match req.state().reports().claim(id, uid).await {
+        Ok(_) => res.flash_info("report claimed"),
+        Err(_) => res.flash_error("report was already claimed"),
+    }
+    Ok(res)
+}
+
+pub async fn resolve(mut req: Request<State>) -> tide::Result {
+    let id = req.param("id")?.to_string();
+    let form = match req.body_form::<ResolveForm>().await {
+        Ok(form) => form,
+        Err(e) => return back(&report_page(&id), &e.to_string()),
+    };
+    if let Err(e) = form.validate() {
+        req.set_form_state(FormState::new(&form).with_errors(&e))?;
+        return Ok(Redirect::new(report_page(&id)).into());
+    }
+
+    let state = req.state().clone();
+    let moderator = req.user().unwrap().clone();
+    let report = match state.reports().get(&id).await {
+        Ok(report) if report.moderator.as_deref() == Some(moderator._id.as_str()) => report,
+        Ok(_) => return back(&report_page(&id), "claim the report before resolving it"),
+        Err(_) => return back("/admin/reports", "report not found"),
+    };
+    if !outcomes(&report).contains(&form.outcome) {
+        return back(&report_page(&id), "only posts can be removed");
+    }
+    let note = form.note.filter(|n| !n.trim().is_empty());
+
+    let target = if form.outcome == Outcome::Dismissed {
+        None
+    } else {
+        match reported_account(&state, &report).await {
+            Ok(target) if policy::can_sanction(&moderator, &target) => Some(target),
+            Ok(_) => return back(&report_page(&id), "you can't act on this account"),
+            Err(_) => return back(&report_page(&id), "the reported account no longer exists"),
+        }
+    };
+
+    // only the moderator whose resolve went through applies the outcome, so
+    // it can't be applied twice
+    let report = match state
+        .reports()
+        .resolve(&id, &moderator._id, form.outcome, note.clone())
+        .await
+    {
+        Ok(report) => report,
+        Err(_) => return back(&report_page(&id), "report was already resolved"),
+    };
+
+    if let Some(target) = target {
+        let detail = Some(format!("report {}", report._id));
+        match form.outcome {
+            Outcome::Warned => {
+                warn(&state, &target, note.as_deref()).await?;
+                req.audit(AuditKind::Warned, &target._id, detail).await?;
+            }
+            Outcome::Suspended if !target.is_suspended() => {
+                let target = suspend_user(&state, target).await?;
+                req.audit(AuditKind::Suspended, &target._id, detail).await?;
+            }
+            Outcome::RemovedContent => {
+                // the author may have deleted it in the meantime
+                match state.posts().remove(&report.target.id).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {
+                        req.audit(AuditKind::ContentRemoved, &target._id, detail)
+                            .await?
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    notify_reporters(&state, &report).await;
+
+    let mut res: Response = Redirect::new("/admin/reports").into();
+    res.flash_info("report resolved");
+    Ok(res)
+}
+
+async fn warn(state: &State, target: &User, note: Option<&str>) -> tide::Result<()> {
+    let body = state.render(
+        "account-warning.txt",
+        &json!({ "username": target.username, "note": note }),
+    )?;
+    state
+        .mailer
+        .send(Email {
+            to: target.username.clone(),
+            subject: String::from("A warning about your account"),
+            body,
+        })
+        .await
+}
+
+/// Lets everyone who filed the report know it was handled. Failures are only
+/// logged, the report is resolved either way.
+async fn notify_reporters(state: &State, report: &Report) {
+    let outcome = report.outcome.map_or("dismissed", |o| o.as_str());
+    for reporter in &report.reporters {
+        let user = match state.users().get_by_id(&reporter.uid).await {
+            Ok(user) => user,
+            Err(_) => continue,
+        };
+        let body = match state.render(
+            "report-outcome.txt",
+            &json!({ "username": user.username, "outcome": outcome }),
+        ) {
+            Ok(body) => body,
+            Err(e) => {
+                tide::log::error!("failed to render report outcome: {}", e);
+                return;
+            }
+        };
+        let email = Email {
+            to: user.username.clone(),
+            subject: String::from("Update on your report"),
+            body,
+        };
+        if let Err(e) = state.mailer.send(email).await {
+            tide::log::error!("failed to send report outcome: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_report_page, outcomes, Report, TargetKind};
+    use crate::repos::report::{Outcome, ReportStatus, ReportTarget};
+
+    fn report(kind: TargetKind) -> Report {
+        Report {
+            _id: String::from("r"),
+            target: ReportTarget {
+                kind,
+                id: String::from("t"),
+            },
+            reporters: Vec::new(),
+            status: ReportStatus::Open,
+            moderator: None,
+            outcome: None,
+            note: None,
+            created_at: 0,
+            resolved_at: None,
+        }
+    }
+
+    #[test]
+    fn only_posts_can_be_removed() {
+        assert!(!outcomes(&report(TargetKind::Account)).contains(&Outcome::RemovedContent));
+        assert!(outcomes(&report(TargetKind::Post)).contains(&Outcome::RemovedContent));
+    }
+
+    #[test]
+    fn escapes_the_target_in_the_form_url() {
+        assert_eq!(
+            new_report_page(TargetKind::Post, "a&b=c"),
+            "/account/report?target_type=post&target_id=a%26b%3Dc"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Stores browser sessions can be kept in, and the middleware loading them
+//! from the session cookie. Idle and absolute expiry are enforced by the
+//! middleware rather than left to each store, so every backend behaves the
+//! same.
+
+mod cookie;
+mod mongo;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_redis_session::RedisSessionStore;
+use async_session::{MemoryStore, Result, Session, SessionStore};
+use async_trait::async_trait;
+use mongodb::Database;
+use tide::http::cookies::{Cookie, CookieJar, Key, SameSite};
+use tide::{Middleware, Next, Request};
+
+use crate::config::Config;
+use crate::registry::State;
+use crate::repos::db_error;
+use crate::timestamp;
+use cookie::EncryptedCookieStore;
+use mongo::MongoSessionStore;
+
+/// When the session started, in seconds since the epoch. Reset on login so
+/// the absolute lifetime counts from there.
+pub const STARTED_AT: &str = "tide.started-at";
+
+/// When the session was last used, in seconds since the epoch.
+const LAST_SEEN_AT: &str = "tide.last-seen-at";
+
+/// Cookie value the session moves to once the request is done, set on login
+/// so an id handed out before it can't be used to ride the new session.
+pub const RENEW_TO: &str = "tide.renew-to";
+
+/// How often the last use is written back at most, sessions aren't saved on
+/// requests that don't change them otherwise.
+const TOUCH_INTERVAL: usize = 60;
+
+/// Session store selected by `session_store`.
+#[derive(Debug, Clone)]
+pub enum SessionBackend {
+    Memory(MemoryStore),
+    Redis(RedisSessionStore),
+    Mongo(MongoSessionStore),
+    Cookie(EncryptedCookieStore),
+}
+
+impl SessionBackend {
+    pub async fn from_config(config: &Config, db: &Database) -> std::io::Result<Self> {
+        match (config.session_store.as_deref(), &config.redis_url) {
+            (Some("mongodb"), _) => Ok(SessionBackend::Mongo(
+                MongoSessionStore::new(db).await.map_err(db_error)?,
+            )),
+            (Some("cookie"), _) => Ok(SessionBackend::Cookie(EncryptedCookieStore::new(
+                config.session_secret.as_bytes(),
+            ))),
+            (Some("memory"), _) | (_, None) => Ok(SessionBackend::Memory(MemoryStore::new())),
+            (_, Some(url)) => RedisSessionStore::new(url.as_str())
+                .map(SessionBackend::Redis)
+                .map_err(std::io::Error::other),
+        }
+    }
+
+    /// Drops expired sessions from stores that don't expire them by
+    /// themselves.
+    pub async fn cleanup(&self) -> Result {
+        match self {
+            SessionBackend::Memory(store) => store.cleanup().await,
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for SessionBackend {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        match self {
+            SessionBackend::Memory(store) => store.load_session(cookie_value).await,
+            SessionBackend::Redis(store) => store.load_session(cookie_value).await,
+            SessionBackend::Mongo(store) => store.load_session(cookie_value).await,
+            SessionBackend::Cookie(store) => store.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        match self {
+            SessionBackend::Memory(store) => store.store_session(session).await,
+            SessionBackend::Redis(store) => store.store_session(session).await,
+            SessionBackend::Mongo(store) => store.store_session(session).await,
+            SessionBackend::Cookie(store) => store.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        match self {
+            SessionBackend::Memory(store) => store.destroy_session(session).await,
+            SessionBackend::Redis(store) => store.destroy_session(session).await,
+            SessionBackend::Mongo(store) => store.destroy_session(session).await,
+            SessionBackend::Cookie(store) => store.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> Result {
+        match self {
+            SessionBackend::Memory(store) => store.clear_store().await,
+            SessionBackend::Redis(store) => store.clear_store().await,
+            SessionBackend::Mongo(store) => store.clear_store().await,
+            SessionBackend::Cookie(store) => store.clear_store().await,
+        }
+    }
+}
+
+/// Loads the session named by the signed session cookie and saves it after
+/// the request, like tide's session middleware, but with the cookie
+/// attributes taken from the config. A session is discarded once it has not
+/// been used for `session_idle_minutes`, or `session_lifetime_hours` after
+/// it started.
+pub struct SessionMiddleware {
+    store: SessionBackend,
+    key: Key,
+    cookie_name: String,
+    cookie_domain: Option<String>,
+    secure: bool,
+    same_site: SameSite,
+    idle: usize,
+    lifetime: usize,
+}
+
+impl SessionMiddleware {
+    pub fn new(store: SessionBackend, config: &Config) -> Self {
+        SessionMiddleware {
+            store,
+            key: Key::derive_from(config.session_secret.as_bytes()),
+            cookie_name: config.session_cookie_name.clone(),
+            cookie_domain: config.session_cookie_domain.clone(),
+            secure: config.cookie_secure(),
+            same_site: match config.session_cookie_same_site.as_str() {
+                "strict" => SameSite::Strict,
+                "none" => SameSite::None,
+                _ => SameSite::Lax,
+            },
+            idle: config.session_idle().as_secs() as usize,
+            lifetime: config.session_lifetime().as_secs() as usize,
+        }
+    }
+
+    /// Value of the session cookie if its signature holds.
+    fn verify(&self, cookie: Cookie<'static>) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        let cookie = jar.signed(&self.key).get(&self.cookie_name)?;
+        Some(cookie.value().to_string())
+    }
+
+    /// Loads the session, destroying it instead when it has expired.
+    async fn load(&self, cookie_value: String, now: usize) -> Option<Session> {
+        let session = match self.store.load_session(cookie_value).await {
+            Ok(session) => session?.validate()?,
+            Err(e) => {
+                tide::log::warn!("failed to load session: {}", e);
+                return None;
+            }
+        };
+        if now < self.expires_at(&session) {
+            return Some(session);
+        }
+        if let Err(e) = self.store.destroy_session(session).await {
+            tide::log::error!("failed to destroy expired session: {}", e);
+        }
+        None
+    }
+
+    /// A new session, only stored once something is put in it so visitors
+    /// who never log in or submit a form don't each leave one behind.
+    fn start(now: usize) -> Session {
+        let mut session = Session::new();
+        session.insert_raw(STARTED_AT, now.to_string());
+        session.insert_raw(LAST_SEEN_AT, now.to_string());
+        session.reset_data_changed();
+        session
+    }
+
+    /// Whichever comes first of the idle and the absolute expiry.
+    fn expires_at(&self, session: &Session) -> usize {
+        let started_at = session.get::<usize>(STARTED_AT).unwrap_or(0);
+        let last_seen_at = session.get::<usize>(LAST_SEEN_AT).unwrap_or(0);
+        (last_seen_at + self.idle).min(started_at + self.lifetime)
+    }
+
+    /// Moves the session to the cookie value left under [`RENEW_TO`],
+    /// dropping it under its old id.
+    async fn renew(&self, session: &mut Session) -> tide::Result<()> {
+        let cookie_value = match session.get::<String>(RENEW_TO) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        if let Err(e) = self.store.destroy_session(session.clone()).await {
+            tide::log::error!("failed to destroy renewed session: {}", e);
+        }
+        // the id can only be chosen through the serialized form
+        let mut value = serde_json::to_value(&*session)?;
+        value["id"] = Session::id_from_cookie_value(&cookie_value)?.into();
+        let mut renewed: Session = serde_json::from_value(value)?;
+        renewed.set_cookie_value(cookie_value);
+        // also marks the data as changed, so it is stored under the new id
+        renewed.remove(RENEW_TO);
+        *session = renewed;
+        Ok(())
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build(self.cookie_name.clone(), value)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .path("/")
+            .finish();
+        if let Some(domain) = &self.cookie_domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+#[async_trait]
+impl Middleware<State> for SessionMiddleware {
+    async fn handle(&self, mut request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let now = timestamp();
+        let cookie = request.cookie(&self.cookie_name);
+        let cookie_value = cookie.clone().and_then(|cookie| self.verify(cookie));
+
+        let (mut session, cookie_value) = match cookie_value {
+            Some(value) => match self.load(value.clone(), now).await {
+                Some(session) => (session, Some(value)),
+                None => (SessionMiddleware::start(now), None),
+            },
+            None => (SessionMiddleware::start(now), None),
+        };
+        let last_seen_at = session.get::<usize>(LAST_SEEN_AT).unwrap_or(0);
+        if now >= last_seen_at + TOUCH_INTERVAL.min(self.idle / 4) {
+            session.insert_raw(LAST_SEEN_AT, now.to_string());
+        }
+
+        request.set_ext(session.clone());
+        let mut response = next.run(request).await;
+
+        if session.is_destroyed() {
+            if let Err(e) = self.store.destroy_session(session).await {
+                tide::log::error!("failed to destroy session: {}", e);
+            }
+            if cookie.is_some() {
+                response.remove_cookie(self.cookie(String::new()));
+            }
+            return Ok(response);
+        }
+
+        self.renew(&mut session).await?;
+        if session.data_changed() {
+            let expires_at = self.expires_at(&session);
+            session.expire_in(Duration::from_secs(expires_at.saturating_sub(now) as u64));
+            let stored = self
+                .store
+                .store_session(session)
+                .await
+                .map_err(|e| tide::http::format_err!("{}", e))?;
+            // stores keyed by id only hand out the cookie value once, the
+            // cookie is still renewed to move its expiry along
+            if let Some(value) = stored.or(cookie_value) {
+                let mut cookie = self.cookie(value);
+                cookie.set_expires(Some(
+                    (UNIX_EPOCH + Duration::from_secs(expires_at as u64)).into(),
+                ));
+                let mut jar = CookieJar::new();
+                jar.signed(&self.key).add(cookie);
+                if let Some(cookie) = jar.get(&self.cookie_name) {
+                    response.insert_cookie(cookie.clone());
+                }
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_session::{MemoryStore, Session, SessionStore};
+    use tide::http::{Method, Url};
+    use tide::Request;
+
+    use super::{SessionBackend, SessionMiddleware, RENEW_TO};
+    use crate::registry::State;
+
+    async fn get(app: &tide::Server<State>, path: &str, cookie: Option<&str>) -> (String, String) {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Get, url);
+        if let Some(cookie) = cookie {
+            req.insert_header("Cookie", cookie);
+        }
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let cookie = res.header("Set-Cookie").unwrap().as_str();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        (cookie, res.body_string().await.unwrap())
+    }
+
+    #[async_std::test]
+    async fn renewing_moves_the_session_to_a_new_id() {
+        let state = State::for_tests();
+        let store = MemoryStore::new();
+        let mut app = tide::with_state(state.clone());
+        app.with(SessionMiddleware::new(
+            SessionBackend::Memory(store.clone()),
+            &state.config,
+        ));
+        app.at("/visit").get(|mut req: Request<State>| async move {
+            req.session_mut().insert("uid", "a")?;
+            Ok("")
+        });
+        app.at("/login").get(|mut req: Request<State>| async move {
+            let value = Session::new().into_cookie_value().unwrap();
+            req.session_mut().insert(RENEW_TO, &value)?;
+            Ok(value)
+        });
+
+        let (cookie, _) = get(&app, "/visit", None).await;
+        let (renewed, value) = get(&app, "/login", Some(&cookie)).await;
+        assert_ne!(cookie, renewed);
+        assert_eq!(store.count().await, 1);
+        let session = store.load_session(value).await.unwrap().unwrap();
+        assert_eq!(session.get::<String>("uid").as_deref(), Some("a"));
+        assert_eq!(session.get::<String>(RENEW_TO), None);
+    }
+
+    #[async_std::test]
+    async fn sessions_are_only_stored_once_they_hold_something() {
+        let state = State::for_tests();
+        let store = MemoryStore::new();
+        let mut app = tide::with_state(state.clone());
+        app.with(SessionMiddleware::new(
+            SessionBackend::Memory(store.clone()),
+            &state.config,
+        ));
+        app.with(crate::csrf::CsrfMiddleware {});
+        app.at("/").get(|_| async { Ok("") });
+        app.at("/form").get(|mut req: Request<State>| async move {
+            Ok(crate::csrf::issue(&mut req)?.unwrap_or_default())
+        });
+
+        let url = Url::parse("http://localhost/").unwrap();
+        let res: tide::http::Response = app
+            .respond(tide::http::Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        assert!(res.header("Set-Cookie").is_none());
+        assert_eq!(store.count().await, 0);
+
+        get(&app, "/form", None).await;
+        assert_eq!(store.count().await, 1);
+    }
+}
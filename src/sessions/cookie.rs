@@ -0,0 +1,68 @@
+use std::fmt;
+
+use async_session::{CookieStore, Result, Session, SessionStore};
+use async_trait::async_trait;
+use tide::http::cookies::{Cookie, CookieJar, Key};
+
+/// Name the encrypted value is bound to, independent of the cookie name so
+/// renaming the cookie doesn't invalidate it.
+const NAME: &str = "session";
+
+/// Browsers drop cookies larger than this.
+const MAX_COOKIE_SIZE: usize = 4096;
+
+/// Keeps the whole session in the cookie, encrypted with AES-GCM so the
+/// client can neither read nor change it. Nothing is kept on the server, so
+/// ending a session from elsewhere relies on the session index.
+#[derive(Clone)]
+pub struct EncryptedCookieStore {
+    key: Key,
+}
+
+impl EncryptedCookieStore {
+    pub fn new(secret: &[u8]) -> Self {
+        EncryptedCookieStore {
+            key: Key::derive_from(secret),
+        }
+    }
+}
+
+impl fmt::Debug for EncryptedCookieStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedCookieStore").finish()
+    }
+}
+
+#[async_trait]
+impl SessionStore for EncryptedCookieStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(NAME, cookie_value));
+        match jar.private(&self.key).get(NAME) {
+            Some(cookie) => CookieStore::new().load_session(cookie.value().into()).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let value = match CookieStore::new().store_session(session).await? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let mut jar = CookieJar::new();
+        jar.private(&self.key).add(Cookie::new(NAME, value));
+        let value = jar.get(NAME).map(|cookie| cookie.value().to_string());
+        if value.as_ref().is_some_and(|v| v.len() > MAX_COOKIE_SIZE) {
+            tide::log::warn!("session cookie exceeds {} bytes", MAX_COOKIE_SIZE);
+        }
+        Ok(value)
+    }
+
+    async fn destroy_session(&self, _session: Session) -> Result {
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        Ok(())
+    }
+}
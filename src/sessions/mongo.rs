@@ -0,0 +1,84 @@
+use std::time::{Duration, SystemTime};
+
+use async_session::{Result, Session, SessionStore};
+use async_trait::async_trait;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{IndexOptions, ReplaceOptions};
+use mongodb::{Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+
+const COLLECTION: &str = "session_store";
+
+/// Session as kept in MongoDB.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSession {
+    /// Id of the session, the cookie carries a value it is derived from.
+    _id: String,
+    /// The session as JSON, the way the redis store keeps it.
+    session: String,
+    /// Read by the TTL index, which removes the document once it has passed.
+    expires_at: Option<DateTime>,
+}
+
+/// Keeps sessions in a MongoDB collection, expired ones are removed by a
+/// TTL index on `expires_at`.
+#[derive(Debug, Clone)]
+pub struct MongoSessionStore {
+    collection: Collection<StoredSession>,
+}
+
+impl MongoSessionStore {
+    /// Creates the TTL index if it is missing.
+    pub async fn new(db: &Database) -> mongodb::error::Result<Self> {
+        let collection = db.collection::<StoredSession>(COLLECTION);
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        collection.create_index(index, None).await?;
+        Ok(MongoSessionStore { collection })
+    }
+}
+
+#[async_trait]
+impl SessionStore for MongoSessionStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        match self.collection.find_one(doc! { "_id": id }, None).await? {
+            // the TTL monitor only runs every minute
+            Some(stored) => Ok(serde_json::from_str::<Session>(&stored.session)?.validate()),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let stored = StoredSession {
+            _id: session.id().to_string(),
+            session: serde_json::to_string(&session)?,
+            expires_at: session
+                .expires_in()
+                .map(|ttl| DateTime::from_system_time(SystemTime::now() + ttl)),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.collection
+            .replace_one(doc! { "_id": &stored._id }, &stored, options)
+            .await?;
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        self.collection
+            .delete_one(doc! { "_id": session.id() }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        self.collection.delete_many(doc! {}, None).await?;
+        Ok(())
+    }
+}
@@ -1,11 +1,8 @@
-use std::collections::HashMap;
-
 use serde::Serialize;
 use serde_json::json;
-use tide::{http, Request, StatusCode};
-use validator::ValidationError;
+use tide::{http, Body, Request, Server, StatusCode};
 
-use crate::{prelude::*, registry::State};
+use crate::{csrf, prelude::*, registry::State};
 
 pub struct TemplateResponse<T: Serialize> {
     request: Request<State>,
@@ -40,7 +37,7 @@ impl<T: Serialize> TemplateResponse<T> {
     }
 
     pub fn with_template(mut self, template: &str) -> Self {
-        let (_, ext) = template.rsplit_once('.').unwrap();
+        let ext = template.rsplit_once('.').map_or("", |(_, ext)| ext);
         self.content_type = http::Mime::from_extension(ext).unwrap_or(tide::http::mime::HTML);
         self.template = template.into();
         self
@@ -54,39 +51,140 @@ impl<T: Serialize> TemplateResponse<T> {
 
 impl<T: Serialize> From<TemplateResponse<T>> for tide::Result {
     fn from(res: TemplateResponse<T>) -> Self {
-        let mut field_errors: HashMap<String, Vec<ValidationError>> = HashMap::new();
-        let mut flash_messages = Vec::new();
-        if let Some(messages) = res.request.flash() {
-            for message in messages {
-                let msg = message.message.clone();
-                let validation =
-                    serde_json::from_str::<HashMap<String, Vec<ValidationError>>>(&msg);
-                if let Ok(validation) = validation {
-                    for (k, v) in validation {
-                        let values = field_errors.entry(k).or_default();
-                        for i in v {
-                            values.push(i);
-                        }
-                    }
-                } else {
-                    flash_messages.push(message);
-                }
-            }
+        let mut request = res.request;
+        let form = request.take_form_state();
+        let flash_messages: Vec<_> = request.flash().into_iter().flatten().collect();
+        let csrf_token = request.ext::<csrf::CsrfToken>().map(|t| t.0.clone());
+        let context = json!({
+            "flash": flash_messages,
+            "claims": request.claims(),
+            "errors": form.errors,
+            "form": form.values,
+            "data": res.data,
+            "csrf_token": csrf_token,
+        });
+
+        if request.wants_json() {
+            csrf::issue(&mut request)?;
+            let res = tide::Response::builder(res.code)
+                .body(Body::from_json(&context)?)
+                .header("Vary", "Accept")
+                .build();
+            return Ok(res);
+        }
+
+        let template = request.state().render(&res.template, &context)?;
+        // pages without a form don't need the token kept
+        if csrf_token.is_some_and(|token| template.contains(&token)) {
+            csrf::issue(&mut request)?;
         }
-        let template = res.request.state().render(
-            &res.template,
-            &json!({
-                "flash": flash_messages,
-                "claims": res.request.claims(),
-                "errors": field_errors,
-                "data": res.data
-            }),
-        )?;
 
         let res = tide::Response::builder(res.code)
             .body(template)
             .content_type(res.content_type)
+            .header("Vary", "Accept")
             .build();
         Ok(res)
     }
 }
+
+/// Wraps `app` so that `/path.json` is served by the `/path` route as if it
+/// had been requested with `Accept: application/json`.
+pub fn with_json_suffix(app: Server<State>) -> Server<()> {
+    let endpoint = move |req: Request<()>| {
+        let app = app.clone();
+        async move {
+            let mut req: http::Request = req.into();
+            if let Some(path) = req.url().path().strip_suffix(".json").map(String::from) {
+                req.url_mut().set_path(&path);
+                req.insert_header("Accept", http::mime::JSON.essence());
+            }
+            let res: http::Response = app.respond(req).await?;
+            Ok(tide::Response::from(res))
+        }
+    };
+
+    let mut root = tide::new();
+    root.at("/").all(endpoint.clone());
+    root.at("*").all(endpoint);
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Url};
+    use tide::{Request, StatusCode};
+
+    use super::{with_json_suffix, TemplateResponse};
+    use crate::prelude::*;
+    use crate::registry::State;
+    use crate::sessions::{SessionBackend, SessionMiddleware};
+
+    fn app() -> tide::Server<()> {
+        let state = State::for_tests();
+        let mut app = tide::with_state(state.clone());
+        app.with(SessionMiddleware::new(
+            SessionBackend::Memory(async_session::MemoryStore::new()),
+            &state.config,
+        ));
+        app.at("/")
+            .get(|req: Request<State>| async move { Ok(req.wants_json().to_string()) });
+        app.at("/page").get(|req: Request<State>| async move {
+            TemplateResponse::new(req, "404.html")
+                .with_data(serde_json::json!({ "shown": true }))
+                .into()
+        });
+        with_json_suffix(app)
+    }
+
+    async fn get(path: &str, accept: Option<&str>) -> tide::http::Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Get, url);
+        if let Some(accept) = accept {
+            req.insert_header("Accept", accept);
+        }
+        app().respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn negotiates_json_from_the_accept_header() {
+        for (accept, json) in [
+            (None, false),
+            (Some("text/html"), false),
+            (Some("*/*"), false),
+            (Some("application/json"), true),
+            (Some("text/html;q=0.5, application/json"), true),
+            (Some("text/html, application/json;q=0.9"), false),
+            (Some("application/json, text/html"), false),
+            (
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                false,
+            ),
+        ] {
+            let mut res = get("/", accept).await;
+            assert_eq!(
+                res.body_string().await.unwrap(),
+                json.to_string(),
+                "{:?}",
+                accept
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn serves_json_for_the_json_suffix() {
+        let mut res = get("/.json", None).await;
+        assert_eq!(res.body_string().await.unwrap(), "true");
+
+        let mut res = get("/page.json", None).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.header("Vary").unwrap().as_str(), "Accept");
+        let context: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(context["data"]["shown"], true);
+
+        let mut res = get("/page", None).await;
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        assert!(res.body_string().await.unwrap().contains("Page not found"));
+        assert_eq!(get("/page.html", None).await.status(), StatusCode::NotFound);
+    }
+}